@@ -0,0 +1,376 @@
+use clap::ValueEnum;
+use fcmc::{
+    AbstractMachine, Driver, ExitPolicy, FcmcProgramState, FcmcTerm, FmcState, FmcTerm, LambdaTerm,
+    Locations, PState, ParseError, Program, Random, Recording, RoundRobin, RunOptions, SLambdaTerm,
+    SState, Schedule, Scheduler, Sink, State, Var,
+};
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+/// Exit code when the input cannot be read, parsed or checked
+pub const INPUT_ERROR: u8 = 1;
+/// Exit code when the machine fails to run the term to completion
+pub const RUN_ERROR: u8 = 3;
+
+/// The abstract machines a term can be run on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Machine {
+    /// The partial abstract machine, for lambda terms
+    Pam,
+    /// The Krivine abstract machine, for lambda terms
+    Kam,
+    /// The sequential abstract machine, for sequential lambda terms
+    Sam,
+    /// The functional machine calculus, with many locations
+    Fmc,
+    /// The concurrent functional machine calculus, with threads and channels
+    Fcmc,
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().expect("no machine is skipped");
+        write!(f, "{}", name.get_name())
+    }
+}
+
+/// What happens to forked threads still running when the main thread finishes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Exit {
+    /// Wait for every thread to finish, failing if they are all blocked on empty channels
+    Wait,
+    /// Stop the other threads as soon as the main thread finishes
+    Terminate,
+}
+
+impl From<Exit> for ExitPolicy {
+    fn from(exit: Exit) -> Self {
+        match exit {
+            Exit::Wait => ExitPolicy::WaitForAll,
+            Exit::Terminate => ExitPolicy::MainExitTerminates,
+        }
+    }
+}
+
+/// How the threads of an FCMC term take turns
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Scheduling {
+    /// Run each thread on its own OS thread
+    Threads,
+    /// Run the threads on one OS thread, one step of each in turn
+    RoundRobin,
+    /// Run the threads on one OS thread, picking the next to step at random from the seed
+    Random,
+}
+
+impl Scheduling {
+    /// The [`Schedule`] to run with, where `seed` is used only by [`Scheduling::Random`]
+    pub fn with_seed(self, seed: u64) -> Schedule {
+        match self {
+            Scheduling::Threads => Schedule::Threads,
+            Scheduling::RoundRobin => Schedule::RoundRobin,
+            Scheduling::Random => Schedule::Random { seed },
+        }
+    }
+}
+
+/// Why a subcommand failed, with the message to report
+pub(crate) enum Failure {
+    /// The input could not be read, parsed or checked
+    Input(String),
+    /// The machine could not run the term to completion
+    Run(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Input(message) | Failure::Run(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A term's source and where it was read from
+pub(crate) struct Input {
+    path: PathBuf,
+    source: String,
+}
+
+impl Input {
+    /// Create an [`Input`] from source that was read from `path`
+    pub(crate) fn new(path: &Path, source: &str) -> Self {
+        Input {
+            path: path.to_path_buf(),
+            source: source.to_string(),
+        }
+    }
+
+    /// Read the file at `path`, or standard input if `path` is `-`
+    pub(crate) fn read(path: &str) -> Result<Self, Failure> {
+        if path == "-" {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .map_err(|e| Failure::Input(format!("<stdin>: error: {}", e)))?;
+            Ok(Input::new(Path::new("<stdin>"), &source))
+        } else {
+            let source = fs::read_to_string(path)
+                .map_err(|e| Failure::Input(format!("{}: error: {}", path, e)))?;
+            Ok(Input::new(Path::new(path), &source))
+        }
+    }
+
+    /// Parse the source as a single term
+    pub(crate) fn parse<T: FromStr<Err = ParseError>>(&self) -> Result<T, Failure> {
+        self.source.parse().map_err(|e| self.failure(e))
+    }
+
+    /// Load the source as an FCMC program, along with the definitions of every file it imports
+    pub(crate) fn load(&self) -> Result<Program, Failure> {
+        Program::load_source(&self.path, &self.source).map_err(|e| Failure::Input(e.to_string()))
+    }
+
+    fn failure<E: Display>(&self, error: E) -> Failure {
+        Failure::Input(format!("{}: {}", self.path.display(), error))
+    }
+}
+
+/// A machine part way through running a term, which is stepped through one transition at a time
+pub(crate) trait Stepping: Display {
+    /// Returns true if the machine has finished running its term
+    fn is_final(&self) -> bool;
+
+    /// Perform a single transition of the machine
+    fn step(&mut self) -> Result<(), Failure>;
+
+    /// Read back the output of the machine, one line per value
+    fn output(&mut self) -> Vec<String>;
+}
+
+/// An [`AbstractMachine`] being stepped through, with how to print its output
+struct Stepper<M: AbstractMachine> {
+    machine: M,
+    lines: fn(M::Output) -> Vec<String>,
+}
+
+impl<M> Stepping for Stepper<M>
+where
+    M: AbstractMachine + Display,
+    M::Closure: Display,
+{
+    fn is_final(&self) -> bool {
+        self.machine.final_()
+    }
+
+    fn step(&mut self) -> Result<(), Failure> {
+        self.machine.step().map_err(|e| Failure::Run(e.to_string()))
+    }
+
+    fn output(&mut self) -> Vec<String> {
+        (self.lines)(self.machine.readback())
+    }
+}
+
+impl<M: AbstractMachine + Display> Display for Stepper<M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.machine)
+    }
+}
+
+/// Parse the input as a term for `machine` and create its start state. An FCMC term may use the
+/// definitions in `program`, and its threads are interleaved by a [`Scheduler`] as the schedule
+/// in `options` sets, one step of each in turn unless it is random
+pub(crate) fn start_stepping(
+    machine: Machine,
+    input: &Input,
+    program: &Program,
+    options: &RunOptions,
+) -> Result<Box<dyn Stepping>, Failure> {
+    Ok(match machine {
+        Machine::Pam => stepper(PState::start(input.parse()?), term_lines),
+        Machine::Kam => stepper(State::start(input.parse()?), term_lines),
+        Machine::Sam => stepper(SState::start(input.parse()?), stack_lines),
+        Machine::Fmc => stepper(FmcState::start(input.parse()?), memory_lines),
+        Machine::Fcmc => {
+            let mut program = program.clone();
+            program.extend(input.load()?);
+            let (term, locations) = prepare(input, &program)?;
+            // each state is shown as it is stepped to, so the steps are not traced as well, and
+            // they are taken by hand so they are not timed
+            let options = RunOptions {
+                trace: Sink::None,
+                timeout: None,
+                ..options.clone()
+            };
+            match options.schedule {
+                Schedule::Threads | Schedule::RoundRobin => stepper(
+                    Scheduler::new(term, locations, RoundRobin::default(), &options),
+                    memory_lines,
+                ),
+                Schedule::Random { seed } => stepper(
+                    Scheduler::new(term, locations, Random::new(seed), &options),
+                    memory_lines,
+                ),
+            }
+        }
+    })
+}
+
+fn stepper<M>(machine: M, lines: fn(M::Output) -> Vec<String>) -> Box<dyn Stepping>
+where
+    M: AbstractMachine + Display + 'static,
+    M::Closure: Display,
+{
+    Box::new(Stepper { machine, lines })
+}
+
+/// Run the term read from `path` on `machine` and print its output, one line per value.
+/// If tracing is set, every state the machine passes through is printed first
+pub fn run(machine: Machine, path: &str, options: &RunOptions) -> ExitCode {
+    report(Input::read(path).and_then(|input| evaluate(machine, &input, options)))
+}
+
+/// Run the FCMC program read from `path` on OS threads as [`run`] does, and write the order its
+/// threads took their steps on channels in to the file `record`, whether or not the run succeeds
+pub fn run_recorded(path: &str, options: &RunOptions, record: &str) -> ExitCode {
+    report(Input::read(path).and_then(|input| {
+        let (term, locations) = prepare(&input, &input.load()?)?;
+        let (memory, recording) = FcmcProgramState::record(term, locations, options);
+        fs::write(record, recording.to_string())
+            .map_err(|e| Failure::Input(format!("{}: error: {}", record, e)))?;
+        let memory = memory.map_err(|e| Failure::Run(e.to_string()))?;
+        Ok(memory_lines(memory))
+    }))
+}
+
+/// Read a recording of the steps a run took on channels from the file at `path`
+pub(crate) fn read_recording(path: &str) -> Result<Recording, Failure> {
+    let source =
+        fs::read_to_string(path).map_err(|e| Failure::Input(format!("{}: error: {}", path, e)))?;
+    source
+        .parse()
+        .map_err(|e| Failure::Input(format!("{}: {}", path, e)))
+}
+
+/// Check that the term read from `path` can be run on `machine`, printing nothing if it can
+pub fn check(machine: Machine, path: &str) -> ExitCode {
+    report(Input::read(path).and_then(|input| {
+        match machine {
+            Machine::Pam | Machine::Kam => input.parse::<LambdaTerm>().map(|_| ()),
+            Machine::Sam => input.parse::<SLambdaTerm>().map(|_| ()),
+            Machine::Fmc => input.parse::<FmcTerm>().map(|_| ()),
+            Machine::Fcmc => prepare(&input, &input.load()?).map(|_| ()),
+        }?;
+        Ok(vec![])
+    }))
+}
+
+/// Print the term read from `path` in the form the machine's parser reads back. An FCMC program
+/// keeps its imports, declarations and definitions
+pub fn fmt(machine: Machine, path: &str) -> ExitCode {
+    report(Input::read(path).and_then(|input| {
+        let formatted = match machine {
+            Machine::Pam | Machine::Kam => input.parse::<LambdaTerm>()?.to_string(),
+            Machine::Sam => input.parse::<SLambdaTerm>()?.to_string(),
+            Machine::Fmc => input.parse::<FmcTerm>()?.to_string(),
+            Machine::Fcmc => input.parse::<Program>()?.to_string(),
+        };
+        Ok(vec![formatted])
+    }))
+}
+
+/// Run the input on `machine`, returning the lines of its output, or failing once the machine has
+/// taken as many steps as the fuel in `options` allows
+pub(crate) fn evaluate(
+    machine: Machine,
+    input: &Input,
+    options: &RunOptions,
+) -> Result<Vec<String>, Failure> {
+    match machine {
+        Machine::Pam => drive::<PState>(input.parse()?, options).map(term_lines),
+        Machine::Kam => drive::<State>(input.parse()?, options).map(term_lines),
+        Machine::Sam => drive::<SState>(input.parse()?, options).map(stack_lines),
+        Machine::Fmc => drive::<FmcState>(input.parse()?, options).map(memory_lines),
+        Machine::Fcmc => run_program(input, &input.load()?, options),
+    }
+}
+
+/// Run `term` on the machine `M`, writing every state it passes through to the trace in
+/// `options`
+fn drive<M>(term: M::Term, options: &RunOptions) -> Result<M::Output, Failure>
+where
+    M: AbstractMachine + Display,
+    M::Closure: Display,
+{
+    Driver::<M>::new(options.fuel)
+        .on_state(|s| options.trace.line(format_args!("{}", s)))
+        .run(term)
+        .map_err(|e| Failure::Run(e.to_string()))
+}
+
+/// Expand the main term of `program`, which was loaded from `input`, and build the table of
+/// the kinds of the locations it uses
+pub(crate) fn prepare(input: &Input, program: &Program) -> Result<(FcmcTerm, Locations), Failure> {
+    let term = program.expand().map_err(|e| input.failure(e))?;
+    let locations = program.locations(&term).map_err(|e| input.failure(e))?;
+    Ok((term, locations))
+}
+
+/// Run the main term of `program`, which was loaded from `input`, on the FCMC machine
+pub(crate) fn run_program(
+    input: &Input,
+    program: &Program,
+    options: &RunOptions,
+) -> Result<Vec<String>, Failure> {
+    let (term, locations) = prepare(input, program)?;
+    let memory = FcmcProgramState::evaluate(term, locations, options)
+        .map_err(|e| Failure::Run(e.to_string()))?;
+    Ok(memory_lines(memory))
+}
+
+/// Print a term read back from a machine as its only line
+fn term_lines<T: Display>(term: T) -> Vec<String> {
+    vec![term.to_string()]
+}
+
+/// Print a stack read back from a machine a line per term, with the top of the stack first
+fn stack_lines<T: Display>(stack: Vec<T>) -> Vec<String> {
+    stack.iter().rev().map(|t| t.to_string()).collect()
+}
+
+/// Print the contents of memory as `location: term` lines, ordered by location so the output
+/// is the same on every run. The order of terms within a location is kept
+fn memory_lines<T: Display>(mut memory: Vec<(Var, T)>) -> Vec<String> {
+    memory.sort_by(|(a, _), (b, _)| a.cmp(b));
+    memory
+        .iter()
+        .map(|(location, term)| format!("{}: {}", location, term))
+        .collect()
+}
+
+/// Print the output lines of a subcommand, or its error to stderr, and return its exit code
+pub(crate) fn report(result: Result<Vec<String>, Failure>) -> ExitCode {
+    match result {
+        Ok(lines) => {
+            let mut out = io::stdout().lock();
+            for line in lines {
+                // stop quietly if the reader of a pipeline has gone away
+                if writeln!(out, "{}", line).is_err() {
+                    break;
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(failure) => {
+            eprintln!("{}", failure);
+            match failure {
+                Failure::Input(_) => ExitCode::from(INPUT_ERROR),
+                Failure::Run(_) => ExitCode::from(RUN_ERROR),
+            }
+        }
+    }
+}
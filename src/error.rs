@@ -0,0 +1,410 @@
+use crate::lambdaterm::Var;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fmt, io};
+
+type LalrpopError<T> = lalrpop_util::ParseError<usize, T, &'static str>;
+
+/// An error produced when a source string cannot be parsed into a term, located in the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// Description of what went wrong
+    pub message: String,
+    /// The offending token, if the error was caused by one
+    pub token: Option<String>,
+    /// The tokens that would have been accepted at this position
+    pub expected: Vec<String>,
+    /// 1-based line of the error
+    pub line: usize,
+    /// 1-based column of the error, counted in characters
+    pub column: usize,
+    /// The full line of source the error occurred on
+    pub source_line: String,
+}
+
+impl ParseError {
+    /// Create a ['ParseError'] from the error returned by a generated parser and the source it was given
+    pub fn new<T: fmt::Display>(source: &str, error: LalrpopError<T>) -> Self {
+        let (offset, message, token, expected) = match error {
+            lalrpop_util::ParseError::InvalidToken { location } => {
+                let token = source[location..].chars().next().map(|c| c.to_string());
+                (location, "invalid token".to_string(), token, vec![])
+            }
+            lalrpop_util::ParseError::UnrecognizedEOF { location, expected } => (
+                location,
+                "unexpected end of input".to_string(),
+                None,
+                expected,
+            ),
+            lalrpop_util::ParseError::UnrecognizedToken {
+                token: (start, token, _),
+                expected,
+            } => (
+                start,
+                "unexpected token".to_string(),
+                Some(token.to_string()),
+                expected,
+            ),
+            lalrpop_util::ParseError::ExtraToken {
+                token: (start, token, _),
+            } => (
+                start,
+                "extra token".to_string(),
+                Some(token.to_string()),
+                vec![],
+            ),
+            lalrpop_util::ParseError::User { error } => (0, error.to_string(), None, vec![]),
+        };
+        let (line, column, source_line) = locate(source, offset);
+        ParseError {
+            message,
+            token,
+            expected: expected.iter().map(|t| describe_terminal(t)).collect(),
+            line,
+            column,
+            source_line,
+        }
+    }
+}
+
+/// Turn a byte offset into `source` into a 1-based line and column, and the text of that line
+fn locate(source: &str, offset: usize) -> (usize, usize, String) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = source[line_start..offset].chars().count() + 1;
+    let source_line = source[line_start..]
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+    (line, column, source_line)
+}
+
+/// Turn the name of a grammar terminal into the text a user would type
+fn describe_terminal(name: &str) -> String {
+    match name {
+        "DOT" => "`.`",
+        "S_COLON" => "`;`",
+        "SKIP" => "`*`",
+        "L_BRACKET" => "`[`",
+        "R_BRACKET" => "`]`",
+        "L_ANGLE" => "`<`",
+        "R_ANGLE" => "`>`",
+        "L_BRACE" => "`{`",
+        "R_BRACE" => "`}`",
+        "L_PAREN" => "`(`",
+        "R_PAREN" => "`)`",
+        "LAMBDA" => "`\\`",
+        "DEF" => "`def`",
+        "EQUALS" => "`=`",
+        "VAR" => "a name",
+        "NAME" => "a definition name",
+        "IMPORT" => "`import`",
+        "STRING" => "a quoted path",
+        "CHAN" => "`chan`",
+        "STACK" => "`stack`",
+        "COMMA" => "`,`",
+        other => other,
+    }
+    .to_string()
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {}", self.message)?;
+        if let Some(token) = &self.token {
+            write!(f, " `{}`", token)?;
+        }
+        writeln!(f, " at line {}, column {}", self.line, self.column)?;
+        let gutter = self.line.to_string().len();
+        writeln!(f, "{:gutter$} |", "")?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        write!(f, "{:gutter$} | {:>column$}", "", "^", column = self.column)?;
+        match self.expected.len() {
+            0 => Ok(()),
+            1 => write!(f, "\nexpected {}", self.expected[0]),
+            _ => write!(f, "\nexpected one of {}", self.expected.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An error produced when the definitions of a [`Program`][crate::program::Program] cannot be expanded
+#[derive(Clone, Debug, PartialEq)]
+pub enum DefinitionError {
+    /// A name is referenced but never defined
+    Undefined { name: Var },
+    /// A name is defined more than once
+    Duplicate { name: Var },
+    /// A definition refers to itself, directly or through the listed chain of names
+    Recursive { cycle: Vec<Var> },
+    /// The program has definitions but no term to run
+    NoMainTerm,
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DefinitionError::Undefined { name } => write!(f, "error: `{}` is not defined", name),
+            DefinitionError::Duplicate { name } => {
+                write!(f, "error: `{}` is defined more than once", name)
+            }
+            DefinitionError::Recursive { cycle } => {
+                write!(f, "error: recursive definition {}", cycle.join(" -> "))
+            }
+            DefinitionError::NoMainTerm => write!(f, "error: the program has no term to run"),
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+/// An error produced when the location declarations of a program do not fit the term it runs
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocationError {
+    /// A location is used but was never declared
+    Undeclared { name: Var },
+    /// A location is declared as more than one kind
+    Conflict { name: Var },
+}
+
+impl fmt::Display for LocationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LocationError::Undeclared { name } => {
+                write!(f, "error: location `{}` is used but not declared", name)
+            }
+            LocationError::Conflict { name } => {
+                write!(
+                    f,
+                    "error: location `{}` is declared as more than one kind",
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LocationError {}
+
+/// An error produced when the text of a [`Recording`][crate::recording::Recording] cannot be read
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingError {
+    /// 1-based line of the error
+    pub line: usize,
+    /// Description of what went wrong
+    pub message: String,
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {} at line {}", self.message, self.line)
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+/// An error produced when a source file, or one of the files it imports, cannot be loaded
+#[derive(Debug)]
+pub struct SourceError {
+    /// The file the error occurred in
+    pub path: PathBuf,
+    pub kind: SourceErrorKind,
+}
+
+#[derive(Debug)]
+pub enum SourceErrorKind {
+    /// The file could not be read
+    Io(io::Error),
+    /// The file could not be parsed
+    Parse(Box<ParseError>),
+    /// The file imports itself through the listed chain of files
+    ImportCycle(Vec<PathBuf>),
+    /// An imported file has a term to run, but imported files may only contain definitions
+    MainInImport,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.path.display())?;
+        match &self.kind {
+            SourceErrorKind::Io(e) => write!(f, "error: {}", e),
+            SourceErrorKind::Parse(e) => write!(f, "{}", e),
+            SourceErrorKind::ImportCycle(cycle) => {
+                let cycle: Vec<String> = cycle.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "error: import cycle {}", cycle.join(" -> "))
+            }
+            SourceErrorKind::MainInImport => {
+                write!(f, "error: imported files may only contain definitions")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+/// An error produced when an abstract machine cannot run a term to completion. `C` is the type of
+/// the machine's closures, and each error carries the closure that was being evaluated
+#[derive(Clone, Debug, PartialEq)]
+pub enum MachineError<C> {
+    /// A pop from a location that holds nothing
+    EmptyLocation { location: Var, closure: C },
+    /// A pop from a location that does not exist
+    UnknownLocation { location: Var, closure: C },
+    /// A variable that is not bound in the environment of its closure
+    UnboundVariable { name: Var, closure: C },
+    /// The machine did not finish within `limit` steps, and stopped while running `closure`
+    /// with the rest of its state as it was: `stack` and `continuation` from the bottom to the
+    /// top, and `memory` as the closures in each location, each from the top. For FCMC the limit
+    /// is on the steps of each thread, and the state is that of the thread
+    StepLimitExceeded {
+        limit: usize,
+        closure: C,
+        stack: Vec<C>,
+        continuation: Vec<C>,
+        memory: Vec<(Var, C)>,
+    },
+    /// Every live thread is blocked popping from an empty channel, listed by thread number with
+    /// the channel and its closure
+    Deadlock { blocked: Vec<(usize, Var, C)> },
+    /// A thread panicked with the given message
+    ThreadPanicked { message: String },
+    /// The threads were stopped after running for `timeout`, leaving `memory` as the closures
+    /// in each location
+    TimedOut {
+        timeout: Duration,
+        memory: Vec<(Var, C)>,
+    },
+    /// The threads were stopped because the run was cancelled, leaving `memory` as the closures
+    /// in each location
+    Cancelled { memory: Vec<(Var, C)> },
+    /// A forked thread failed. Threads are numbered from 1 in the order they are forked, and
+    /// `forked` is the closure the thread started with
+    InThread {
+        thread: usize,
+        forked: C,
+        error: Box<MachineError<C>>,
+    },
+}
+
+impl<C: fmt::Display> fmt::Display for MachineError<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::EmptyLocation { location, closure } => write!(
+                f,
+                "error: cannot pop from `{}` because it is empty, while running {}",
+                location, closure
+            ),
+            MachineError::UnknownLocation { location, closure } => write!(
+                f,
+                "error: cannot pop from `{}` because it does not exist, while running {}",
+                location, closure
+            ),
+            MachineError::UnboundVariable { name, closure } => write!(
+                f,
+                "error: variable `{}` is not bound, while running {}",
+                name, closure
+            ),
+            MachineError::StepLimitExceeded {
+                limit,
+                closure,
+                stack,
+                continuation,
+                memory,
+            } => {
+                write!(
+                    f,
+                    "error: the machine did not finish within {} steps, stopping while running {}",
+                    limit, closure
+                )?;
+                for closure in stack.iter().rev() {
+                    write!(f, "\n  the stack holds {}", closure)?;
+                }
+                for closure in continuation.iter().rev() {
+                    write!(f, "\n  the continuation holds {}", closure)?;
+                }
+                write_memory(f, memory)
+            }
+            MachineError::Deadlock { blocked } => {
+                write!(
+                    f,
+                    "error: deadlock, every thread is waiting on an empty channel"
+                )?;
+                for (thread, channel, closure) in blocked {
+                    write!(
+                        f,
+                        "\n  thread {} is waiting on `{}`, while running {}",
+                        thread, channel, closure
+                    )?;
+                }
+                Ok(())
+            }
+            MachineError::ThreadPanicked { message } => {
+                write!(f, "error: a thread panicked: {}", message)
+            }
+            MachineError::TimedOut { timeout, memory } => {
+                write!(f, "error: the machine did not finish within {:?}", timeout)?;
+                write_memory(f, memory)
+            }
+            MachineError::Cancelled { memory } => {
+                write!(f, "error: the run was cancelled")?;
+                write_memory(f, memory)
+            }
+            MachineError::InThread {
+                thread,
+                forked,
+                error,
+            } => write!(
+                f,
+                "{}\n  in thread {}, forked to run {}",
+                error, thread, forked
+            ),
+        }
+    }
+}
+
+/// Write what was left in memory when the threads were stopped, one closure per line
+fn write_memory<C: fmt::Display>(f: &mut Formatter<'_>, memory: &[(Var, C)]) -> fmt::Result {
+    for (location, closure) in memory {
+        write!(f, "\n  `{}` holds {}", location, closure)?;
+    }
+    Ok(())
+}
+
+impl<C: fmt::Debug + fmt::Display> std::error::Error for MachineError<C> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ParseError;
+    use crate::fcmc::FcmcTerm;
+
+    #[test]
+    fn locates_error() {
+        let err = "[x]~a.\n  ~a<y>>y".parse::<FcmcTerm>().unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 8);
+        assert_eq!(err.token, Some(">".to_string()));
+        assert_eq!(err.source_line, "  ~a<y>>y");
+    }
+
+    #[test]
+    fn renders_caret() {
+        let err = "~a<y".parse::<FcmcTerm>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: unexpected end of input at line 1, column 5\n  |\n1 | ~a<y\n  |     ^\nexpected `>`"
+        );
+    }
+
+    #[test]
+    fn reports_invalid_token() {
+        let err: ParseError = "[x]a.%".parse::<FcmcTerm>().unwrap_err();
+        assert_eq!(err.message, "invalid token");
+        assert_eq!(err.column, 6);
+        assert!(err.expected.is_empty());
+    }
+}
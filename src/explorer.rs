@@ -0,0 +1,274 @@
+use crate::error::MachineError;
+use crate::fcmc::{ExitPolicy, FcmcClosure, FcmcTerm, Locations, Snapshot};
+use crate::lambdaterm::Var;
+use crate::scheduler::Pool;
+use crate::sink::Sink;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Everything an FCMC term can do under the interleavings of its threads
+#[derive(Debug, Default, PartialEq)]
+pub struct Exploration {
+    /// The distinct final states of memory, in the order they were found. Each is sorted by
+    /// location, keeping the order of the terms within a location
+    pub outcomes: Vec<Vec<(Var, FcmcTerm)>>,
+    /// The schedules that deadlock or fail, as the thread that took each step, and their errors
+    pub failures: Vec<(Vec<usize>, MachineError<FcmcClosure>)>,
+    /// The number of distinct states the threads passed through
+    pub states: usize,
+}
+
+/// Run a term whose locations have the kinds given in `locations` under every interleaving of its
+/// threads, with forked threads left at the end as set by `exit`. A state reached by more than
+/// one interleaving is only explored once, so each failure is reported with the first schedule
+/// found to reach it. The term must have finitely many states, or this never returns
+pub fn explore(term: FcmcTerm, locations: Locations, exit: ExitPolicy) -> Exploration {
+    let mut exploration = Exploration::default();
+    let mut seen = HashSet::new();
+    let mut outcomes = HashSet::new();
+    let mut pending = vec![(Pool::new(term, locations, None), vec![])];
+    while let Some((mut pool, schedule)) = pending.pop() {
+        if !seen.insert(pool.snapshot()) {
+            continue;
+        }
+        if pool.final_(exit) {
+            let mut memory = pool.readback();
+            memory.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if outcomes.insert(memory.clone()) {
+                exploration.outcomes.push(memory);
+            }
+            continue;
+        }
+        let ready = pool.ready();
+        if ready.is_empty() {
+            exploration.failures.push((schedule, pool.deadlock()));
+            continue;
+        }
+        // pushed in reverse so that lower numbered threads are explored first
+        for id in ready.into_iter().rev() {
+            let mut next = pool.duplicate();
+            let mut schedule = schedule.clone();
+            schedule.push(id);
+            match next.step(id, &Sink::None) {
+                Ok(()) => pending.push((next, schedule)),
+                Err(error) => exploration.failures.push((schedule, error)),
+            }
+        }
+    }
+    exploration.states = seen.len();
+    exploration
+}
+
+/// Find every final state of memory a term can reach once all of its threads have finished,
+/// with its locations inferred from their names. Like [`explore`], but only one of the
+/// interleavings that differ in the order of commuting steps is followed: steps that touch no
+/// channel commute with every other step, and steps on different channels with each other
+pub fn reachable_outcomes(term: FcmcTerm) -> Exploration {
+    let locations = Locations::infer(&term);
+    let mut exploration = Exploration::default();
+    // the threads left asleep when each state was explored
+    let mut seen: HashMap<Snapshot, BTreeSet<usize>> = HashMap::new();
+    let mut outcomes = HashSet::new();
+    let mut pending = vec![(Pool::new(term, locations, None), vec![], BTreeSet::new())];
+    while let Some((mut pool, schedule, mut asleep)) = pending.pop() {
+        let snapshot = pool.snapshot();
+        if let Some(explored) = seen.get(&snapshot) {
+            // only the threads that were asleep then but are not now are left to explore
+            if explored.is_subset(&asleep) {
+                continue;
+            }
+            asleep = asleep.intersection(explored).copied().collect();
+        }
+        seen.insert(snapshot, asleep.clone());
+        if pool.final_(ExitPolicy::WaitForAll) {
+            let mut memory = pool.readback();
+            memory.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if outcomes.insert(memory.clone()) {
+                exploration.outcomes.push(memory);
+            }
+            continue;
+        }
+        let ready = pool.ready();
+        if ready.is_empty() {
+            exploration.failures.push((schedule, pool.deadlock()));
+            continue;
+        }
+        // a step touching no channel cannot be disabled by other threads, and commutes with their
+        // steps, so taking it alone reaches every outcome
+        let steps: Vec<usize> = match ready.iter().find(|&&id| pool.channel(id).is_none()) {
+            Some(&id) => vec![id],
+            None => ready
+                .into_iter()
+                .filter(|id| !asleep.contains(id))
+                .collect(),
+        };
+        for id in steps {
+            let channel = pool.channel(id).cloned();
+            // the threads explored from here before this one need not be stepped after it, unless
+            // their steps are on the same channel and so do not commute with it
+            let still_asleep = asleep
+                .iter()
+                .filter(|&&other| pool.channel(other) != channel.as_ref())
+                .copied()
+                .collect();
+            let mut next = pool.duplicate();
+            let mut schedule = schedule.clone();
+            schedule.push(id);
+            match next.step(id, &Sink::None) {
+                Ok(()) => pending.push((next, schedule, still_asleep)),
+                Err(error) => exploration.failures.push((schedule, error)),
+            }
+            asleep.insert(id);
+        }
+    }
+    exploration.states = seen.len();
+    exploration
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MachineError;
+    use crate::explorer::{explore, reachable_outcomes, Exploration};
+    use crate::fcmc::{ExitPolicy, FcmcTerm, Locations};
+
+    fn outcomes(term: &str, exit: ExitPolicy) -> Vec<Vec<(String, String)>> {
+        let term: FcmcTerm = term.parse().unwrap();
+        let locations = Locations::infer(&term);
+        let exploration = explore(term, locations, exit);
+        assert!(exploration.failures.is_empty());
+        let mut outcomes: Vec<Vec<(String, String)>> = exploration
+            .outcomes
+            .into_iter()
+            .map(|memory| {
+                memory
+                    .into_iter()
+                    .map(|(location, term)| (location, term.to_string()))
+                    .collect()
+            })
+            .collect();
+        outcomes.sort();
+        outcomes
+    }
+
+    fn out(terms: &[&str]) -> Vec<(String, String)> {
+        terms
+            .iter()
+            .map(|term| ("~out".to_string(), term.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_every_order() {
+        let found = outcomes("{[a]~out}.[b]~out", ExitPolicy::WaitForAll);
+        assert_eq!(found, vec![out(&["a", "b"]), out(&["b", "a"])]);
+    }
+
+    #[test]
+    fn merges_equal_states() {
+        // pushes to stacks commute, so there is one outcome however the threads interleave
+        let term: FcmcTerm = "{[a]x.[b]x}.[c]y.[d]y".parse().unwrap();
+        let exploration = explore(
+            term.clone(),
+            Locations::infer(&term),
+            ExitPolicy::WaitForAll,
+        );
+        assert_eq!(exploration.outcomes.len(), 1);
+        // after the fork, the main thread is before, between or after its pushes, and the
+        // forked thread is before or between its pushes or has been dropped once finished
+        assert_eq!(exploration.states, 1 + 3 * 2 + 3);
+    }
+
+    #[test]
+    fn forked_threads_may_be_stopped() {
+        let found = outcomes("{[a]~out}.[b]c", ExitPolicy::MainExitTerminates);
+        // the forked thread only pushes if it steps before the main thread's last step
+        let stack = ("c".to_string(), "b".to_string());
+        let pushed = ("~out".to_string(), "a".to_string());
+        assert_eq!(found, vec![vec![stack.clone()], vec![stack, pushed]]);
+    }
+
+    #[test]
+    fn reports_deadlocking_schedule() {
+        // the program deadlocks if the second thread takes the value meant for the first
+        let term: FcmcTerm = "{~a<x>.[x]~b}.{~a<x>.*}.[y]~a.~b<w>.*".parse().unwrap();
+        let locations = Locations::infer(&term);
+        let exploration = explore(term, locations, ExitPolicy::MainExitTerminates);
+        assert_eq!(exploration.outcomes, vec![vec![]]);
+        assert!(!exploration.failures.is_empty());
+        for (schedule, error) in exploration.failures {
+            assert!(schedule.contains(&2));
+            assert!(matches!(error, MachineError::Deadlock { .. }));
+        }
+    }
+
+    #[test]
+    fn reports_failing_thread() {
+        let term: FcmcTerm = "{z}.[x]a".parse().unwrap();
+        let exploration = explore(
+            term.clone(),
+            Locations::infer(&term),
+            ExitPolicy::WaitForAll,
+        );
+        assert!(exploration.outcomes.is_empty());
+        let schedules: Vec<&Vec<usize>> = exploration.failures.iter().map(|(s, _)| s).collect();
+        assert_eq!(schedules, vec![&vec![0, 1], &vec![0, 0, 1]]);
+        assert!(exploration
+            .failures
+            .iter()
+            .all(|(_, error)| matches!(error, MachineError::InThread { thread: 1, .. })));
+    }
+
+    fn sorted(exploration: &Exploration) -> Vec<String> {
+        let mut outcomes: Vec<String> = exploration
+            .outcomes
+            .iter()
+            .map(|memory| format!("{:?}", memory))
+            .collect();
+        outcomes.sort();
+        outcomes
+    }
+
+    #[test]
+    fn reduces_commuting_steps() {
+        let term: FcmcTerm = "{[a]~out.[b]x.[c]x}.{[d]~out.[e]~in}.[f]y.~in<g>.[g]~out"
+            .parse()
+            .unwrap();
+        for term in [FcmcTerm::term1(), FcmcTerm::term3(), term.clone()] {
+            let locations = Locations::infer(&term);
+            let every = explore(term.clone(), locations, ExitPolicy::WaitForAll);
+            let reduced = reachable_outcomes(term);
+            assert_eq!(sorted(&reduced), sorted(&every));
+            assert!(reduced.states <= every.states);
+        }
+        let every = explore(
+            term.clone(),
+            Locations::infer(&term),
+            ExitPolicy::WaitForAll,
+        );
+        let reduced = reachable_outcomes(term);
+        // d is always pushed to ~out before e, and a can come before, between or after them
+        assert_eq!(reduced.outcomes.len(), 3);
+        assert!(reduced.states < every.states);
+    }
+
+    #[test]
+    fn keeps_races_on_a_channel() {
+        let term: FcmcTerm = "{[a]~out}.{[b]~out}.[c]~out".parse().unwrap();
+        // every order of the three pushes
+        assert_eq!(reachable_outcomes(term).outcomes.len(), 6);
+    }
+
+    #[test]
+    fn reduces_deadlocks() {
+        // the second thread must wait for the value pushed after the first has passed its on
+        let term: FcmcTerm = "{~a<x>.[x]~b}.{~a<x>.*}.[y]~a.~b<w>.[v]~a.*"
+            .parse()
+            .unwrap();
+        let exploration = reachable_outcomes(term);
+        assert_eq!(exploration.outcomes, vec![vec![]]);
+        assert!(exploration
+            .failures
+            .iter()
+            .all(|(_, error)| matches!(error, MachineError::Deadlock { .. })));
+        assert!(!exploration.failures.is_empty());
+    }
+}
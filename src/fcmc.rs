@@ -1,10 +1,28 @@
-use crate::lambdaterm::Var;
+use crate::error::{LocationError, MachineError, ParseError};
+use crate::lambdaterm::{LambdaTerm, Var};
+use crate::parser::FcmcTermParser;
+use crate::recording::{Access, Event, Fork, Recording};
+use crate::scheduler::{Random, RoundRobin, Scheduler};
+use crate::sink::Sink;
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Formatter;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{fmt, thread};
 
-#[derive(Clone, Debug, PartialEq)]
+/// A term of the functional concurrent machine calculus.
+///
+/// In concrete syntax a pop `a<x>.M`, a push `[N]a.M` and a fork `{N}.M` scope over everything
+/// to their right, and `M;N` associates to the right, so `a<x>.M;N` pops into `M;N`.
+/// Any term can appear inside `[..]`, `{..}` and `(..)`.
+/// [`Display`][fmt::Display] inserts only the parentheses this requires, so printing a term and
+/// parsing the result gives back an equal term
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FcmcTerm {
     Skip,
     Variable {
@@ -72,19 +90,16 @@ impl FcmcTerm {
         }
     }
 
-    /// Return a list of channel names accessed in a [FcmcTerm].
-    /// Channel names start with a ~, stacks do not need to be created ahead of time
-    pub fn channel_scan(&self) -> HashSet<Var> {
+    /// Return the names of all locations accessed in a [FcmcTerm]
+    pub fn locations(&self) -> HashSet<Var> {
         fn traverse(term: &FcmcTerm, mut list: HashSet<Var>) -> HashSet<Var> {
             match term {
-                FcmcTerm::Skip => return list,
-                FcmcTerm::Variable { .. } => return list,
+                FcmcTerm::Skip => list,
+                FcmcTerm::Variable { .. } => list,
                 FcmcTerm::Pop {
                     location_id, next, ..
                 } => {
-                    if location_id.starts_with("~") {
-                        list.insert(location_id.clone());
-                    }
+                    list.insert(location_id.clone());
                     traverse(next, list)
                 }
                 FcmcTerm::Push {
@@ -92,9 +107,7 @@ impl FcmcTerm {
                     location_id,
                     next,
                 } => {
-                    if location_id.starts_with("~") {
-                        list.insert(location_id.clone());
-                    }
+                    list.insert(location_id.clone());
                     traverse(term, traverse(next, list))
                 }
                 FcmcTerm::Seq { term, next } => traverse(term, traverse(next, list)),
@@ -104,6 +117,110 @@ impl FcmcTerm {
         let list = HashSet::new();
         traverse(self, list)
     }
+
+    /// Returns true if self prints as a sequence or ends in a prefix that scopes over everything
+    /// to its right, so it needs parentheses on the left of a `;`
+    fn is_open(&self) -> bool {
+        match self {
+            FcmcTerm::Pop { next, .. } | FcmcTerm::Push { next, .. } => **next != FcmcTerm::Skip,
+            FcmcTerm::Fork { cont, .. } => **cont != FcmcTerm::Skip,
+            FcmcTerm::Seq { .. } => true,
+            FcmcTerm::Skip | FcmcTerm::Variable { .. } => false,
+        }
+    }
+
+    /// Return all [`Var`]s that occur in self, bound or free
+    pub fn get_used_names(&self) -> HashSet<Var> {
+        match self {
+            FcmcTerm::Skip => HashSet::new(),
+            FcmcTerm::Variable { name } => [name.to_string()].into(),
+            FcmcTerm::Pop { arg, next, .. } => {
+                let mut names = next.get_used_names();
+                names.insert(arg.to_string());
+                names
+            }
+            FcmcTerm::Push { term, next, .. }
+            | FcmcTerm::Seq { term, next }
+            | FcmcTerm::Fork {
+                forked: term,
+                cont: next,
+            } => &term.get_used_names() | &next.get_used_names(),
+        }
+    }
+
+    /// Return the [`Var`]s that occur in self without being bound by an enclosing pop
+    pub fn free_variables(&self) -> HashSet<Var> {
+        match self {
+            FcmcTerm::Skip => HashSet::new(),
+            FcmcTerm::Variable { name } => [name.to_string()].into(),
+            FcmcTerm::Pop { arg, next, .. } => {
+                let mut names = next.free_variables();
+                names.remove(arg);
+                names
+            }
+            FcmcTerm::Push { term, next, .. }
+            | FcmcTerm::Seq { term, next }
+            | FcmcTerm::Fork {
+                forked: term,
+                cont: next,
+            } => &term.free_variables() | &next.free_variables(),
+        }
+    }
+
+    /// Rename the free occurrences of a [`Var`] in self and return the new term
+    pub fn renamed(&self, old: &Var, new: &Var) -> Self {
+        self.substitute(old, &FcmcTerm::new_variable(new))
+    }
+
+    /// Perform a capture-avoiding substitution of `new` for the free occurrences of `old`,
+    /// renaming any pop that would otherwise bind a free variable of `new`
+    pub fn substitute(&self, old: &Var, new: &FcmcTerm) -> Self {
+        match self {
+            FcmcTerm::Skip => FcmcTerm::Skip,
+            FcmcTerm::Variable { name } => {
+                if name == old {
+                    new.clone()
+                } else {
+                    self.clone()
+                }
+            }
+            FcmcTerm::Pop {
+                location_id,
+                arg,
+                next,
+            } => {
+                if arg == old {
+                    self.clone()
+                } else if new.free_variables().contains(arg) {
+                    let mut used = &self.get_used_names() | &new.get_used_names();
+                    used.insert(old.clone());
+                    let z = LambdaTerm::fresh_from_used(used);
+                    FcmcTerm::new_pop(
+                        location_id.clone(),
+                        &z,
+                        next.renamed(arg, &z).substitute(old, new),
+                    )
+                } else {
+                    FcmcTerm::new_pop(location_id.clone(), arg, next.substitute(old, new))
+                }
+            }
+            FcmcTerm::Push {
+                term,
+                location_id,
+                next,
+            } => FcmcTerm::new_push(
+                term.substitute(old, new),
+                location_id.clone(),
+                next.substitute(old, new),
+            ),
+            FcmcTerm::Seq { term, next } => {
+                FcmcTerm::new_seq(term.substitute(old, new), next.substitute(old, new))
+            }
+            FcmcTerm::Fork { forked, cont } => {
+                FcmcTerm::new_fork(forked.substitute(old, new), cont.substitute(old, new))
+            }
+        }
+    }
 }
 
 impl fmt::Display for FcmcTerm {
@@ -127,10 +244,14 @@ impl fmt::Display for FcmcTerm {
                 FcmcTerm::Skip => write!(f, "[{}]{}", term, location_id),
                 _ => write!(f, "[{}]{}.{}", term, location_id, next),
             },
-            FcmcTerm::Seq { term, next } => match **next {
-                FcmcTerm::Skip => write!(f, "{}", term),
-                _ => write!(f, "{};{}", term, next),
-            },
+            // the left of a `;` is parenthesised only when it would otherwise extend over the `;`
+            FcmcTerm::Seq { term, next } => {
+                if term.is_open() {
+                    write!(f, "({});{}", term, next)
+                } else {
+                    write!(f, "{};{}", term, next)
+                }
+            }
             FcmcTerm::Fork { forked, cont } => match **cont {
                 FcmcTerm::Skip => write!(f, "{{{}}}", forked),
                 _ => write!(f, "{{{}}}.{}", forked, cont),
@@ -139,7 +260,17 @@ impl fmt::Display for FcmcTerm {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl FromStr for FcmcTerm {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FcmcTermParser::new()
+            .parse(s)
+            .map_err(|e| ParseError::new(s, e))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FcmcClosure {
     term: FcmcTerm,
     env: Vec<(Var, FcmcClosure)>,
@@ -150,6 +281,10 @@ impl FcmcClosure {
         FcmcClosure { term, env }
     }
 
+    pub fn term(&self) -> &FcmcTerm {
+        &self.term
+    }
+
     pub fn retrieve_term(mut self) -> FcmcTerm {
         match self.term {
             FcmcTerm::Skip => self.term,
@@ -158,7 +293,7 @@ impl FcmcClosure {
                     FcmcTerm::new_variable(&name)
                 } else {
                     let env_last = self.env.pop().unwrap();
-                    if &env_last.0 == &name {
+                    if env_last.0 == name {
                         env_last.1.retrieve_term()
                     } else {
                         self.term = FcmcTerm::Variable { name };
@@ -215,127 +350,757 @@ impl fmt::Display for FcmcClosure {
     }
 }
 
+/// How a location is shared between the threads of a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationKind {
+    /// Shared by every thread; popping from an empty channel waits for another thread to push
+    Channel,
+    /// Local to each thread, and empty in a newly forked thread
+    Stack,
+}
+
+impl fmt::Display for LocationKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LocationKind::Channel => write!(f, "chan"),
+            LocationKind::Stack => write!(f, "stack"),
+        }
+    }
+}
+
+/// A table giving the [`LocationKind`] of each location a program uses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Locations {
+    kinds: HashMap<Var, LocationKind>,
+}
+
+impl Locations {
+    /// Create an empty ['Locations'] table
+    pub fn new() -> Self {
+        Locations::default()
+    }
+
+    /// Create the table for a term without declarations, where locations whose names start
+    /// with a ~ are channels and all others are stacks
+    pub fn infer(term: &FcmcTerm) -> Self {
+        let kinds = term
+            .locations()
+            .into_iter()
+            .map(|name| {
+                let kind = if name.starts_with('~') {
+                    LocationKind::Channel
+                } else {
+                    LocationKind::Stack
+                };
+                (name, kind)
+            })
+            .collect();
+        Locations { kinds }
+    }
+
+    /// Declare `name` as a location of the given kind.
+    /// Returns an error if it has already been declared as a different kind
+    pub fn declare(&mut self, name: Var, kind: LocationKind) -> Result<(), LocationError> {
+        match self.kinds.get(&name) {
+            Some(&declared) if declared != kind => Err(LocationError::Conflict { name }),
+            _ => {
+                self.kinds.insert(name, kind);
+                Ok(())
+            }
+        }
+    }
+
+    /// Return the kind of a location, or [`None`] if it has not been declared
+    pub fn kind(&self, name: &str) -> Option<LocationKind> {
+        self.kinds.get(name).copied()
+    }
+
+    /// Return the names of every location of the given kind
+    pub fn of_kind(&self, kind: LocationKind) -> impl Iterator<Item = &Var> {
+        self.kinds
+            .iter()
+            .filter(move |(_, k)| **k == kind)
+            .map(|(name, _)| name)
+    }
+
+    /// Check that every location accessed by `term` has been declared
+    pub fn check(&self, term: &FcmcTerm) -> Result<(), LocationError> {
+        let mut undeclared: Vec<Var> = term
+            .locations()
+            .into_iter()
+            .filter(|name| !self.kinds.contains_key(name))
+            .collect();
+        undeclared.sort();
+        match undeclared.into_iter().next() {
+            Some(name) => Err(LocationError::Undeclared { name }),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Memory {
+    locations: Locations,
     channels: HashMap<Var, (Sender<FcmcClosure>, Receiver<FcmcClosure>)>,
     stacks: HashMap<Var, Vec<FcmcClosure>>,
 }
 
 impl Memory {
-    /// create a ['Memory'][Self] containing senders and receivers for each location
-    /// locations in FCMC are channels or stacks, indicated by a ~
-    pub fn new(locations: HashSet<Var>) -> Self {
+    /// create a ['Memory'][Self] containing senders and receivers for each channel in `locations`.
+    /// Locations missing from the table are treated as stacks once they have been pushed to
+    pub fn new(locations: Locations) -> Self {
         let mut channels = HashMap::new();
-        let mut stacks = HashMap::new();
-        for name in locations.iter() {
+        let stacks = HashMap::new();
+        for name in locations.of_kind(LocationKind::Channel) {
             let (send, recv) = unbounded();
             channels.insert(name.clone(), (send, recv));
         }
-        Memory { channels, stacks }
+        Memory {
+            locations,
+            channels,
+            stacks,
+        }
     }
 
-    pub fn pop(&mut self, location: Var) -> FcmcClosure {
-        if location.starts_with("~") {
-            // channels start with ~
-            self.channels
-                .get(&location)
-                .expect("No location exists with specified name")
-                .1
-                .recv()
-                .expect("Failed to pop from location")
-        } else {
-            self.stacks
-                .get_mut(&location)
-                .ok_or("Specified location doesn't exist".to_string())
-                .unwrap()
-                .pop()
-                .ok_or(
-                    "Term cannot be executed. Pop action encountered but local stack is empty."
-                        .to_string(),
-                )
-                .unwrap()
-        }
+    /// Returns true if `location` is shared between threads
+    fn is_channel(&self, location: &str) -> bool {
+        self.locations.kind(location) == Some(LocationKind::Channel)
     }
 
-    fn pop_all(&mut self, location: Var) -> Vec<FcmcClosure> {
-        if location.starts_with("~") {
-            self.channels
-                .get(&location)
-                .expect("No location exists with specified name")
-                .1
-                .try_iter()
-                .collect()
-        } else {
-            // the result of .drain() is reversed because it goes from the bottom of the stack to the top
-            self.stacks
-                .get_mut(&location)
-                .ok_or("Specified location doesn't exist".to_string())
-                .unwrap()
-                .drain(..)
-                .rev()
-                .collect()
+    /// The channel `location`, for the thread running `closure`
+    fn channel(
+        &self,
+        location: &str,
+        closure: &FcmcClosure,
+    ) -> Result<&(Sender<FcmcClosure>, Receiver<FcmcClosure>), MachineError<FcmcClosure>> {
+        self.channels
+            .get(location)
+            .ok_or_else(|| unknown_location(location, closure))
+    }
+
+    /// Pop the closure on top of `location`, or the oldest closure sent if it is a channel, for
+    /// the thread running `closure`. Returns [`None`] if there is nothing to pop; waiting for a
+    /// closure to be sent to a channel is up to the thread. Fails if `location` is not in the
+    /// table and nothing has been pushed to it
+    pub fn pop(
+        &mut self,
+        location: Var,
+        closure: &FcmcClosure,
+    ) -> Result<Option<FcmcClosure>, MachineError<FcmcClosure>> {
+        if self.is_channel(&location) {
+            return Ok(self.channel(&location, closure)?.1.try_recv().ok());
+        }
+        match self.stacks.get_mut(&location) {
+            Some(stack) => Ok(stack.pop()),
+            None if self.locations.kind(&location).is_some() => Ok(None),
+            None => Err(unknown_location(&location, closure)),
         }
     }
 
-    pub fn push(&mut self, location: Var, closure: FcmcClosure) {
-        if location.starts_with("~") {
-            self.channels
-                .get(&location)
-                .unwrap_or_else(|| panic!("No location exists with specified name: {location}"))
+    /// Push `pushed` to `location` for the thread running `closure`. A location missing from
+    /// the table is a stack, created by the first push to it
+    pub fn push(
+        &mut self,
+        location: Var,
+        pushed: FcmcClosure,
+        closure: &FcmcClosure,
+    ) -> Result<(), MachineError<FcmcClosure>> {
+        if self.is_channel(&location) {
+            // memory holds the receiver of every channel, so sending only fails without one
+            self.channel(&location, closure)?
                 .0
-                .send(closure)
-                .expect("Failed to push to location")
+                .send(pushed)
+                .map_err(|_| unknown_location(&location, closure))
         } else {
-            self.stacks.entry(location).or_default().push(closure);
+            self.stacks.entry(location).or_default().push(pushed);
+            Ok(())
         }
     }
 
     pub fn is_empty(&self, location: Var) -> bool {
-        if location.starts_with("~") {
+        if self.is_channel(&location) {
             self.channels
                 .get(&location)
-                .expect("No location exists with specified name")
-                .1
-                .is_empty()
+                .map_or(true, |(_, recv)| recv.is_empty())
         } else {
-            location.is_empty()
+            self.stacks.get(&location).map_or(true, Vec::is_empty)
         }
     }
 
-    fn readback(&mut self) -> Vec<(Var, FcmcTerm)> {
-        let mut res = vec![];
-        for name in self.channels.clone().keys() {
-            let closures = self.pop_all(name.clone());
-            for closure in closures {
-                res.push((name.clone(), closure.retrieve_term()));
+    /// The closures in `location`, from the oldest sent or the bottom of the stack, leaving them
+    /// in place. A channel is emptied and filled again, so no other thread may be using it
+    fn peek(&self, location: &str) -> Vec<FcmcClosure> {
+        match self.channels.get(location) {
+            Some((send, recv)) => {
+                let closures: Vec<FcmcClosure> = recv.try_iter().collect();
+                for closure in &closures {
+                    send.send(closure.clone())
+                        .expect("the channel keeps its receiver");
+                }
+                closures
             }
+            None => self.stacks.get(location).cloned().unwrap_or_default(),
         }
-        for name in self.stacks.clone().keys() {
-            let closures = self.pop_all(name.clone());
-            for closure in closures {
-                res.push((name.clone(), closure.retrieve_term()));
-            }
+    }
+
+    pub(crate) fn readback(&mut self) -> Vec<(Var, FcmcTerm)> {
+        self.drain()
+            .into_iter()
+            .map(|(name, closure)| (name, closure.retrieve_term()))
+            .collect()
+    }
+
+    /// The closures in each stack, from the top, leaving them in place
+    fn local(&self) -> Vec<(Var, FcmcClosure)> {
+        let mut res = vec![];
+        for (name, stack) in self.stacks.iter() {
+            res.extend(
+                stack
+                    .iter()
+                    .rev()
+                    .map(|closure| (name.clone(), closure.clone())),
+            );
+        }
+        res
+    }
+
+    /// Empty every location, returning the closures that were in it
+    pub(crate) fn drain(&mut self) -> Vec<(Var, FcmcClosure)> {
+        let mut res = vec![];
+        for (name, (_, recv)) in self.channels.iter() {
+            res.extend(recv.try_iter().map(|closure| (name.clone(), closure)));
+        }
+        for (name, stack) in self.stacks.iter_mut() {
+            // the result of .drain() is reversed because it goes from the bottom of the stack to the top
+            res.extend(stack.drain(..).rev().map(|closure| (name.clone(), closure)));
         }
         res
     }
 }
 
+/// The error for the thread running `closure` using `location`, which does not exist
+fn unknown_location(location: &str, closure: &FcmcClosure) -> MachineError<FcmcClosure> {
+    MachineError::UnknownLocation {
+        location: location.to_string(),
+        closure: closure.clone(),
+    }
+}
+
 impl Clone for Memory {
     fn clone(&self) -> Self {
         // when Memory is cloned for a new thread, channels should be clones but stacks need
         // to be empty, since they are thread local
         Memory {
+            locations: self.locations.clone(),
             channels: self.channels.clone(),
             stacks: HashMap::new(),
         }
     }
 }
 
+/// The state of a group of threads sharing their channels, which compares equal for the same
+/// state reached by different interleavings of the threads
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Snapshot {
+    /// By thread number
+    threads: Vec<ThreadSnapshot>,
+    channels: BTreeMap<Var, Vec<FcmcClosure>>,
+    /// The number the next thread forked will get
+    next_id: usize,
+}
+
+/// The part of a [`Snapshot`] local to one thread
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct ThreadSnapshot {
+    id: usize,
+    closure: FcmcClosure,
+    continuation: Vec<FcmcClosure>,
+    /// Only the stacks that are not empty, as popping the last closure leaves an empty stack
+    stacks: BTreeMap<Var, Vec<FcmcClosure>>,
+}
+
+/// Take a [`Snapshot`] of `threads`, which share their channels and must not be running
+pub(crate) fn snapshot(threads: &BTreeMap<usize, FcmcThreadState>) -> Snapshot {
+    let Some(first) = threads.values().next() else {
+        return Snapshot {
+            threads: vec![],
+            channels: BTreeMap::new(),
+            next_id: 1,
+        };
+    };
+    Snapshot {
+        threads: threads
+            .values()
+            .map(|thread| ThreadSnapshot {
+                id: thread.id,
+                closure: thread.closure.clone(),
+                continuation: thread.continuation.clone(),
+                stacks: thread
+                    .memory
+                    .stacks
+                    .iter()
+                    .filter(|(_, stack)| !stack.is_empty())
+                    .map(|(name, stack)| (name.clone(), stack.clone()))
+                    .collect(),
+            })
+            .collect(),
+        channels: first
+            .memory
+            .channels
+            .keys()
+            .map(|name| (name.clone(), first.memory.peek(name)))
+            .collect(),
+        next_id: first.threads.next_id.load(Ordering::Relaxed),
+    }
+}
+
+/// Copy `threads`, which share their channels and must not be running. The copies share channels
+/// of their own with the same contents, and number the threads they fork on their own, so
+/// stepping them leaves `threads` as they were
+pub(crate) fn duplicate(
+    threads: &BTreeMap<usize, FcmcThreadState>,
+) -> BTreeMap<usize, FcmcThreadState> {
+    let Some(first) = threads.values().next() else {
+        return BTreeMap::new();
+    };
+    let mut channels = HashMap::new();
+    for name in first.memory.channels.keys() {
+        let (send, recv) = unbounded();
+        for closure in first.memory.peek(name) {
+            send.send(closure).expect("the channel keeps its receiver");
+        }
+        channels.insert(name.clone(), (send, recv));
+    }
+    let shared = Threads::new(first.threads.deadline);
+    let next_id = first.threads.next_id.load(Ordering::Relaxed);
+    shared.next_id.store(next_id, Ordering::Relaxed);
+    let shared = Arc::new(shared);
+    threads
+        .iter()
+        .map(|(id, thread)| {
+            let memory = Memory {
+                locations: thread.memory.locations.clone(),
+                channels: channels.clone(),
+                stacks: thread.memory.stacks.clone(),
+            };
+            let copy = FcmcThreadState {
+                memory,
+                threads: shared.clone(),
+                ..thread.clone()
+            };
+            (*id, copy)
+        })
+        .collect()
+}
+
+/// What happens to forked threads that are still running when the main thread finishes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExitPolicy {
+    /// Wait for every thread to finish before reading back memory, failing with a
+    /// [`Deadlock`][MachineError::Deadlock] if the threads left are all waiting on empty channels
+    #[default]
+    WaitForAll,
+    /// Stop every other thread as soon as the main thread finishes
+    MainExitTerminates,
+}
+
+/// How the threads of a program take turns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Schedule {
+    /// Run every thread on its own OS thread, leaving the interleaving to the OS
+    #[default]
+    Threads,
+    /// Run every thread on the calling thread, one step of each in turn
+    RoundRobin,
+    /// Run every thread on the calling thread, choosing which steps next at random. The same
+    /// seed always gives the same interleaving
+    Random { seed: u64 },
+}
+
+/// How [`FcmcProgramState::evaluate`] runs a term
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Where each step of every thread, and each thread forked, is written
+    pub trace: Sink,
+    pub exit: ExitPolicy,
+    pub schedule: Schedule,
+    /// Take the steps on channels in the order of this recording, when running on OS threads
+    pub replay: Option<Recording>,
+    /// The number of steps each thread may take before the run stops, if it is limited
+    pub fuel: Option<usize>,
+    /// How long the threads may run for, if it is limited
+    pub timeout: Option<Duration>,
+    /// Stops the run when cancelled
+    pub cancel: Option<CancelToken>,
+}
+
+/// Stops the runs it is given to from any thread, including threads waiting on empty channels.
+/// Clones share whether they have been cancelled, and a run given a token that has already been
+/// cancelled stops before its first step
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<Cancellation>,
+}
+
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    /// The threads of the runs watching the token
+    runs: Mutex<Vec<Weak<Threads>>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Stop every run watching this token, and every run given it from now on
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for run in self.inner.runs.lock().unwrap().drain(..) {
+            if let Some(threads) = run.upgrade() {
+                threads.cancel();
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Stop `threads` when the token is cancelled
+    fn watch(&self, threads: &Arc<Threads>) {
+        let mut runs = self.inner.runs.lock().unwrap();
+        // checked under the lock, so a token cancelled since is seen here or stops the run
+        if self.is_cancelled() {
+            threads.cancel();
+            return;
+        }
+        runs.retain(|run| run.strong_count() > 0);
+        runs.push(Arc::downgrade(threads));
+    }
+}
+
+/// Why the threads of a run were stopped before they finished
+#[derive(Clone, Copy, Debug)]
+enum Interruption {
+    TimedOut,
+    Cancelled,
+}
+
+/// A thread forked while running a program
+#[derive(Debug)]
+struct Forked {
+    id: usize,
+    /// The closure the thread was forked to run
+    closure: FcmcClosure,
+    handle: JoinHandle<Result<(), MachineError<FcmcClosure>>>,
+}
+
+/// The threads that have not finished, and what the blocked ones are waiting for
+#[derive(Debug)]
+struct Live {
+    running: usize,
+    /// The channel and closure of each thread waiting on an empty channel, by thread number
+    blocked: BTreeMap<usize, (Var, FcmcClosure)>,
+    /// The blocked threads, once every thread has been found waiting on an empty channel
+    deadlock: Option<Vec<(usize, Var, FcmcClosure)>>,
+    /// The steps taken on channels and the threads forked so far, if the run is being recorded
+    recording: Option<Recording>,
+    /// The steps on channels left to take, if a recording is being replayed
+    replaying: Option<VecDeque<Event>>,
+    /// The numbers the threads forked by each thread are given when replaying a recording, by
+    /// the number of the thread that forks them
+    numbering: HashMap<usize, VecDeque<usize>>,
+}
+
+impl Live {
+    fn blocked(&self) -> Vec<(usize, Var, FcmcClosure)> {
+        self.blocked
+            .iter()
+            .map(|(id, (location, closure))| (*id, location.clone(), closure.clone()))
+            .collect()
+    }
+
+    /// Returns true if replaying a recording, and `event` is not the next step to take
+    fn waiting_for_turn(&self, event: &Event) -> bool {
+        let next = self.replaying.as_ref().and_then(VecDeque::front);
+        next.map_or(false, |next| next != event)
+    }
+
+    /// Record that a thread has taken `event`, a step on a channel
+    fn took(&mut self, event: Event) {
+        if let Some(left) = &mut self.replaying {
+            left.pop_front();
+            // the threads waiting for their turn are among the blocked ones, so they are all
+            // woken to check whether it has come
+            self.running += self.blocked.len();
+            self.blocked.clear();
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.events.push(event);
+        }
+    }
+}
+
+/// The threads of a program, shared by all of them. Pushes and pops on channels take the lock on
+/// [`Live`], so a thread cannot be recorded as blocked while a closure it could pop is being sent
+#[derive(Debug)]
+struct Threads {
+    /// The main thread is 0, and forked threads are numbered from 1 in the order they are forked,
+    /// unless a recording being replayed gives their numbers
+    next_id: AtomicUsize,
+    forked: Mutex<VecDeque<Forked>>,
+    live: Mutex<Live>,
+    /// Notified whenever a thread blocks, finishes, or pushes to a channel
+    changed: Condvar,
+    /// Set when the remaining threads should stop at their next step
+    stopping: AtomicBool,
+    /// When the threads are stopped if they are still running, if the run has a timeout
+    deadline: Option<Instant>,
+    /// Why the threads were stopped, if it was by a timeout or a cancellation
+    interrupted: Mutex<Option<Interruption>>,
+}
+
+impl Threads {
+    fn new(deadline: Option<Instant>) -> Self {
+        Threads {
+            next_id: AtomicUsize::new(1),
+            forked: Mutex::new(VecDeque::new()),
+            live: Mutex::new(Live {
+                running: 1,
+                blocked: BTreeMap::new(),
+                deadlock: None,
+                recording: None,
+                replaying: None,
+                numbering: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+            stopping: AtomicBool::new(false),
+            deadline,
+            interrupted: Mutex::new(None),
+        }
+    }
+
+    /// The number of the next thread forked by the thread `parent`: the one it was given in the
+    /// recording being replayed, or else the next unused one. Recorded if the run is
+    fn number_fork(&self, parent: usize) -> usize {
+        let mut live = self.live.lock().unwrap();
+        let child = live
+            .numbering
+            .get_mut(&parent)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(recording) = &mut live.recording {
+            recording.forks.push(Fork { parent, child });
+        }
+        child
+    }
+
+    /// Returns true once the threads should stop, which they do when the deadline has passed
+    fn stopping(&self) -> bool {
+        if self
+            .deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+        {
+            self.interrupt(Interruption::TimedOut);
+        }
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Stop the threads because of `interruption`, unless they are already stopping for another
+    /// reason. This may be called with the lock on [`Live`] held, so the threads waiting are
+    /// woken without it
+    fn interrupt(&self, interruption: Interruption) {
+        let mut interrupted = self.interrupted.lock().unwrap();
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            *interrupted = Some(interruption);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stop the threads because the run was cancelled
+    fn cancel(&self) {
+        self.interrupt(Interruption::Cancelled);
+        // as in `stop`, no thread is left between checking `stopping` and waiting
+        let _live = self.live.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    fn interruption(&self) -> Option<Interruption> {
+        *self.interrupted.lock().unwrap()
+    }
+
+    /// Wait until the threads change, or until the deadline so that the threads see that it
+    /// has passed
+    fn sleep<'a>(&self, live: MutexGuard<'a, Live>) -> MutexGuard<'a, Live> {
+        match self.deadline {
+            Some(deadline) if !self.stopping() => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                self.changed.wait_timeout(live, timeout).unwrap().0
+            }
+            _ => self.changed.wait(live).unwrap(),
+        }
+    }
+
+    /// Make every thread stop at its next step, including those waiting on channels
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // taking the lock means no thread is between checking `stopping` and waiting
+        let _live = self.live.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Record that a thread has finished
+    fn finished(&self) {
+        self.live.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Wake the threads waiting on the channel `location`, after a closure has been pushed to it
+    fn pushed(&self, location: &Var) {
+        let mut live = self.live.lock().unwrap();
+        // the waiting threads count as running from now, even before they take the lock, so
+        // that they are not mistaken for being blocked
+        let before = live.blocked.len();
+        live.blocked
+            .retain(|_, (waiting_on, _)| waiting_on != location);
+        live.running += before - live.blocked.len();
+        self.changed.notify_all();
+    }
+
+    /// Wait until the channel `location` has something to pop, for the thread `id` which is
+    /// running `closure`, or until the threads are stopped
+    fn wait_for(&self, id: usize, memory: &Memory, location: Var, closure: &FcmcClosure) {
+        let live = self.live.lock().unwrap();
+        // a closure pushed since the thread tried to pop is only announced under the lock
+        if !memory.is_empty(location.clone()) || self.stopping() {
+            return;
+        }
+        drop(self.block(live, id, location, closure));
+    }
+
+    /// Wait until it is the turn of the thread `id`, which is running `closure`, to take `event`
+    /// when replaying a recording. Returns the lock on [`Live`] for the step to be taken under, or
+    /// [`None`] if the threads are stopped instead
+    fn turn(&self, id: usize, event: &Event, closure: &FcmcClosure) -> Option<MutexGuard<Live>> {
+        let mut live = self.live.lock().unwrap();
+        while live.waiting_for_turn(event) {
+            if self.stopping() {
+                return None;
+            }
+            // a thread waiting for its turn counts as blocked on the channel, so a recording the
+            // run has strayed from is reported as a deadlock rather than waited on forever
+            live = self.block(live, id, event.channel.clone(), closure);
+        }
+        Some(live)
+    }
+
+    /// Record the thread `id`, which is running `closure`, as blocked on `location` and wait
+    /// until it is woken. The threads are stopped once every thread, including the main thread,
+    /// is blocked
+    fn block<'a>(
+        &'a self,
+        mut live: MutexGuard<'a, Live>,
+        id: usize,
+        location: Var,
+        closure: &FcmcClosure,
+    ) -> MutexGuard<'a, Live> {
+        live.running -= 1;
+        live.blocked.insert(id, (location, closure.clone()));
+        // only a running thread can push, so the blocked threads will wait forever. Once the
+        // main thread has finished, this is left to the exit policy instead
+        if live.running == 0 && live.blocked.contains_key(&0) {
+            live.deadlock = Some(live.blocked());
+            self.stopping.store(true, Ordering::SeqCst);
+        }
+        self.changed.notify_all();
+        if !self.stopping() {
+            live = self.sleep(live);
+        }
+        // unless a push to the channel already counted it as running
+        if live.blocked.remove(&id).is_some() {
+            live.running += 1;
+        }
+        live
+    }
+
+    /// Wait for the threads still running once the main thread has finished or stopped, as set
+    /// by `exit`. Returns the error of the first forked thread to fail, or a deadlock if every
+    /// remaining thread is waiting on an empty channel
+    fn wait(&self, exit: ExitPolicy) -> Result<(), MachineError<FcmcClosure>> {
+        let mut live = self.live.lock().unwrap();
+        if exit == ExitPolicy::WaitForAll {
+            while live.running > 0 {
+                live = self.sleep(live);
+            }
+            // threads stopped by a timeout or cancellation may not have left `blocked` yet
+            if live.deadlock.is_none() && !live.blocked.is_empty() && self.interruption().is_none()
+            {
+                live.deadlock = Some(live.blocked());
+            }
+        }
+        let deadlock = live.deadlock.take();
+        drop(live);
+        self.stop();
+        // a thread that failed may be why the others are blocked, so it is reported first
+        self.join()?;
+        deadlock.map_or(Ok(()), |blocked| Err(MachineError::Deadlock { blocked }))
+    }
+
+    /// Wait for every forked thread to finish, including those forked by threads that are
+    /// waited for, and return the error of the first one to fail
+    fn join(&self) -> Result<(), MachineError<FcmcClosure>> {
+        let mut failure = None;
+        loop {
+            // the lock must not be held while joining, as the thread may still fork
+            let next = self.forked.lock().unwrap().pop_front();
+            let Some(forked) = next else {
+                break;
+            };
+            let outcome = forked
+                .handle
+                .join()
+                .expect("panics are caught by the thread");
+            if let (Err(error), None) = (outcome, &failure) {
+                failure = Some(MachineError::InThread {
+                    thread: forked.id,
+                    forked: forked.closure,
+                    error: box error,
+                });
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+/// What a thread did in a transition, which whatever runs the thread acts on
+#[derive(Debug)]
+pub enum Transition {
+    /// The thread changed only itself or its stacks
+    Stepped,
+    /// The thread pushed to a channel, so threads waiting on it can continue
+    Pushed(Var),
+    /// The thread tried to pop from an empty channel, and is unchanged
+    Blocked(Var),
+    /// The thread forked a new thread, which is ready to run
+    Forked(FcmcThreadState),
+}
+
 #[derive(Clone, Debug)]
 pub struct FcmcThreadState {
     closure: FcmcClosure,
     continuation: Vec<FcmcClosure>,
-    memory: Memory,
+    pub(crate) memory: Memory,
+    /// Where each step of this thread, and of every thread it forks, is written
+    trace: Sink,
+    /// The number of this thread, which is 0 for the main thread
+    id: usize,
+    threads: Arc<Threads>,
+    /// The number of steps this thread, and each thread it forks, may take, if they are limited
+    pub(crate) fuel: Option<usize>,
+    /// The number of steps this thread has taken
+    steps: usize,
 }
 
 impl FcmcThreadState {
@@ -344,25 +1109,109 @@ impl FcmcThreadState {
             closure,
             continuation,
             memory,
+            trace: Sink::None,
+            id: 0,
+            threads: Arc::new(Threads::new(None)),
+            fuel: None,
+            steps: 0,
         }
     }
 
-    fn final_(&self) -> bool {
+    pub fn final_(&self) -> bool {
         match self.closure.term {
             FcmcTerm::Skip => self.continuation.is_empty(),
             _ => false,
         }
     }
 
-    pub fn run_thread(&mut self) -> Result<(), String> {
-        while !self.final_() {
+    /// Run the thread until it finishes, or until the program stops its threads
+    pub fn run_thread(&mut self) -> Result<(), MachineError<FcmcClosure>> {
+        while !self.final_() && !self.threads.stopping() {
+            self.trace.line(format_args!("{}", self.closure));
             self.step()?;
         }
         Ok(())
     }
 
-    #[allow(unused_must_use)]
-    fn step(&mut self) -> Result<(), String> {
+    /// The number of this thread, which is 0 for the main thread and counts up from 1 in the
+    /// order threads are forked
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn closure(&self) -> &FcmcClosure {
+        &self.closure
+    }
+
+    /// The channel the next step of this thread pushes to or pops from, if it touches one. Any
+    /// other step changes only the thread itself and its stacks, so it commutes with the steps
+    /// of every other thread
+    pub fn channel(&self) -> Option<&Var> {
+        match &self.closure.term {
+            FcmcTerm::Pop { location_id, .. } | FcmcTerm::Push { location_id, .. }
+                if self.memory.is_channel(location_id) =>
+            {
+                Some(location_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// The step this thread takes next on a channel, if its next step touches one
+    fn event(&self) -> Option<Event> {
+        let channel = self.channel()?.clone();
+        let access = match self.closure.term {
+            FcmcTerm::Push { .. } => Access::Push,
+            _ => Access::Pop,
+        };
+        Some(Event {
+            thread: self.id,
+            access,
+            channel,
+        })
+    }
+
+    /// The channel this thread is waiting on, if its next step pops from an empty one
+    pub fn waiting_on(&self) -> Option<&Var> {
+        match &self.closure.term {
+            FcmcTerm::Pop { location_id, .. }
+                if self.memory.is_channel(location_id)
+                    && self.memory.is_empty(location_id.clone()) =>
+            {
+                Some(location_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Perform a single transition of this thread, leaving it to the caller to run forked
+    /// threads and to wait on empty channels. Fails once the thread has taken all the steps its
+    /// fuel allows
+    pub fn transition(&mut self) -> Result<Transition, MachineError<FcmcClosure>> {
+        if self.fuel == Some(self.steps) {
+            return Err(self.step_limit_exceeded(self.steps));
+        }
+        let transition = self.apply()?;
+        // trying to pop from an empty channel leaves the thread as it was
+        if !matches!(transition, Transition::Blocked(_)) {
+            self.steps += 1;
+        }
+        Ok(transition)
+    }
+
+    /// The error for stopping this thread after `limit` steps. Its memory is the stacks of the
+    /// thread, as the channels are shared with threads that may still be running
+    pub(crate) fn step_limit_exceeded(&self, limit: usize) -> MachineError<FcmcClosure> {
+        MachineError::StepLimitExceeded {
+            limit,
+            closure: self.closure.clone(),
+            stack: vec![],
+            continuation: self.continuation.clone(),
+            memory: self.memory.local(),
+        }
+    }
+
+    fn apply(&mut self) -> Result<Transition, MachineError<FcmcClosure>> {
         match self.closure.term.clone() {
             FcmcTerm::Skip => {
                 if !self.final_() {
@@ -370,12 +1219,15 @@ impl FcmcThreadState {
                 }
             }
             FcmcTerm::Variable { name } => {
-                let env_last = self
-                    .closure
-                    .env
-                    .pop()
-                    .expect(&*format!("Unable to pop {} from env", name));
-                if name.clone() == env_last.0 {
+                let env_last =
+                    self.closure
+                        .env
+                        .pop()
+                        .ok_or_else(|| MachineError::UnboundVariable {
+                            name: name.clone(),
+                            closure: self.closure.clone(),
+                        })?;
+                if name == env_last.0 {
                     self.closure = env_last.1;
                 }
             }
@@ -384,20 +1236,31 @@ impl FcmcThreadState {
                 arg,
                 next,
             } => {
-                let stack_last = self.memory.pop(location_id);
+                let Some(stack_last) = self.memory.pop(location_id.clone(), &self.closure)? else {
+                    if self.memory.is_channel(&location_id) {
+                        return Ok(Transition::Blocked(location_id));
+                    }
+                    return Err(MachineError::EmptyLocation {
+                        location: location_id,
+                        closure: self.closure.clone(),
+                    });
+                };
                 self.closure.term = *next;
-                self.closure.env.push((arg.clone(), stack_last.clone()));
+                self.closure.env.push((arg, stack_last));
             }
             FcmcTerm::Push {
                 term,
                 location_id,
                 next,
             } => {
+                let shared = self.memory.is_channel(&location_id);
+                let pushed = FcmcClosure::new(*term, self.closure.env.clone());
+                self.memory
+                    .push(location_id.clone(), pushed, &self.closure)?;
                 self.closure.term = *next;
-                self.memory.push(
-                    location_id,
-                    FcmcClosure::new(*term, self.closure.env.clone()),
-                );
+                if shared {
+                    return Ok(Transition::Pushed(location_id));
+                }
             }
             FcmcTerm::Seq { term, next } => {
                 self.continuation
@@ -405,22 +1268,85 @@ impl FcmcThreadState {
                 self.closure.term = *term;
             }
             FcmcTerm::Fork { forked, cont } => {
-                let mut new_thread = FcmcThreadState::new(
-                    FcmcClosure::new(*forked, self.closure.env.clone()),
-                    vec![],
-                    self.memory.clone(),
-                );
-                thread::spawn(move || {
-                    println!("New thread spawned: {}", new_thread.closure.term);
-                    new_thread.run_thread();
-                });
+                let closure = FcmcClosure::new(*forked, self.closure.env.clone());
+                let mut new_thread = FcmcThreadState::new(closure, vec![], self.memory.clone());
+                new_thread.trace = self.trace.clone();
+                new_thread.fuel = self.fuel;
+                new_thread.threads = self.threads.clone();
+                new_thread.id = self.threads.number_fork(self.id);
                 self.closure.term = *cont;
+                return Ok(Transition::Forked(new_thread));
+            }
+        }
+        Ok(Transition::Stepped)
+    }
+
+    /// Perform a single transition of this thread, running forked threads on their own OS
+    /// threads and waiting on an empty channel until something is pushed to it
+    fn step(&mut self) -> Result<(), MachineError<FcmcClosure>> {
+        let transition = match self.event() {
+            // steps on channels are taken under the lock on the live threads, so that they are
+            // recorded and replayed in the order they are taken
+            Some(event) => {
+                let threads = self.threads.clone();
+                let Some(mut live) = threads.turn(self.id, &event, &self.closure) else {
+                    // the program is stopping, so this thread stops waiting
+                    return Ok(());
+                };
+                let transition = self.transition()?;
+                if !matches!(transition, Transition::Blocked(_)) {
+                    live.took(event);
+                    self.threads.changed.notify_all();
+                }
+                transition
+            }
+            None => self.transition()?,
+        };
+        match transition {
+            Transition::Stepped => {}
+            Transition::Pushed(location) => self.threads.pushed(&location),
+            // the pop is tried again at the next step
+            Transition::Blocked(location) => {
+                self.threads
+                    .wait_for(self.id, &self.memory, location, &self.closure)
+            }
+            Transition::Forked(mut new_thread) => {
+                let id = new_thread.id;
+                let closure = new_thread.closure.clone();
+                self.threads.live.lock().unwrap().running += 1;
+                let handle = thread::spawn(move || {
+                    let spawned = &new_thread.closure.term;
+                    new_thread
+                        .trace
+                        .line(format_args!("New thread {} spawned: {}", id, spawned));
+                    // a thread that panics is reported like one that got stuck, and it must
+                    // still be recorded as finished so that nothing waits for it
+                    let outcome = panic::catch_unwind(AssertUnwindSafe(|| new_thread.run_thread()))
+                        .unwrap_or_else(|payload| {
+                            let message = payload
+                                .downcast_ref::<&str>()
+                                .map(|s| s.to_string())
+                                .or_else(|| payload.downcast_ref::<String>().cloned())
+                                .unwrap_or_else(|| "unknown cause".to_string());
+                            Err(MachineError::ThreadPanicked { message })
+                        });
+                    new_thread.threads.finished();
+                    outcome
+                });
+                self.threads.forked.lock().unwrap().push_back(Forked {
+                    id,
+                    closure,
+                    handle,
+                });
             }
         }
         Ok(())
     }
 }
 
+/// What an FCMC run leaves in memory, or the error it stopped with
+pub type Outcome = Result<Vec<(Var, FcmcTerm)>, MachineError<FcmcClosure>>;
+
 #[derive(Clone, Debug)]
 pub struct FcmcProgramState {
     main_thread: FcmcThreadState,
@@ -429,7 +1355,7 @@ pub struct FcmcProgramState {
 impl FcmcProgramState {
     pub fn new(closure: FcmcClosure, memory: Memory, continuation: Vec<FcmcClosure>) -> Self {
         FcmcProgramState {
-            main_thread: FcmcThreadState::new(closure, continuation, memory.clone()),
+            main_thread: FcmcThreadState::new(closure, continuation, memory),
         }
     }
 
@@ -437,27 +1363,120 @@ impl FcmcProgramState {
         FcmcProgramState::new(FcmcClosure::new(t, vec![]), m, vec![])
     }
 
-    pub fn run(term: FcmcTerm) -> Vec<(Var, FcmcTerm)> {
-        let locations = term.channel_scan();
+    /// Run a term without location declarations, where locations whose names start with a ~
+    /// are channels and all others are stacks, writing nothing
+    pub fn run(term: FcmcTerm) -> Outcome {
+        let locations = Locations::infer(&term);
+        FcmcProgramState::run_with_locations(term, locations, &Sink::None)
+    }
+
+    /// Run a term whose locations have the kinds given in `locations`, writing each step of every
+    /// thread and then the output to `sink`
+    pub fn run_with_locations(term: FcmcTerm, locations: Locations, sink: &Sink) -> Outcome {
+        let options = RunOptions {
+            trace: sink.clone(),
+            ..RunOptions::default()
+        };
+        let ans = FcmcProgramState::evaluate(term, locations, &options)?;
+        sink.line(format_args!("OUTPUT:"));
+        for (n, t) in ans.iter().rev() {
+            sink.line(format_args!("{}: {}", n, t));
+        }
+        Ok(ans)
+    }
+
+    /// Run a term whose locations have the kinds given in `locations`, returning the final state of
+    /// memory. Once the main thread finishes, the forked threads are waited for or stopped as
+    /// set by the [`ExitPolicy`], and if any of them failed the error of the first is returned.
+    /// The threads run concurrently or are interleaved on this thread as set by the
+    /// [`Schedule`]. The threads are all stopped once the run times out or is cancelled, and what
+    /// they left in memory is returned with the error. Nothing is written unless a sink to trace
+    /// to is set
+    pub fn evaluate(term: FcmcTerm, locations: Locations, options: &RunOptions) -> Outcome {
+        match options.schedule {
+            Schedule::Threads => FcmcProgramState::run_threads(term, locations, options, false).0,
+            Schedule::RoundRobin => {
+                Scheduler::new(term, locations, RoundRobin::default(), options).finish()
+            }
+            Schedule::Random { seed } => {
+                Scheduler::new(term, locations, Random::new(seed), options).finish()
+            }
+        }
+    }
+
+    /// Run a term on OS threads as [`evaluate`][Self::evaluate] does, whatever the [`Schedule`],
+    /// and return the order its threads took their steps on channels in along with its outcome.
+    /// Running the term again with the [`Recording`] as [`RunOptions::replay`] gives the same run
+    pub fn record(
+        term: FcmcTerm,
+        locations: Locations,
+        options: &RunOptions,
+    ) -> (Outcome, Recording) {
+        FcmcProgramState::run_threads(term, locations, options, true)
+    }
+
+    /// Run a term with each thread on its own OS thread, recording the steps taken on channels if
+    /// `record` is set
+    fn run_threads(
+        term: FcmcTerm,
+        locations: Locations,
+        options: &RunOptions,
+        record: bool,
+    ) -> (Outcome, Recording) {
         let memory = Memory::new(locations);
         let mut state = FcmcProgramState::start(term, memory);
-        state
-            .main_thread
-            .run_thread()
-            .expect("Failed to start main thread.");
-
-        // print final state of memory
-        let ans = state.main_thread.memory.readback();
-        let len = ans.len();
-        println!("OUTPUT:");
-        for (i, (n, t)) in ans.iter().rev().enumerate() {
-            print!("{}: {}", n, t);
-            if i < len - 1 {
-                println!(", ");
+        state.main_thread.trace = options.trace.clone();
+        state.main_thread.fuel = options.fuel;
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+        let threads = Arc::new(Threads::new(deadline));
+        state.main_thread.threads = threads.clone();
+        if let Some(cancel) = &options.cancel {
+            cancel.watch(&threads);
+        }
+        {
+            let mut live = threads.live.lock().unwrap();
+            live.recording = record.then(Recording::default);
+            if let Some(replay) = &options.replay {
+                live.replaying = Some(replay.events.iter().cloned().collect());
+                for fork in &replay.forks {
+                    let children = live.numbering.entry(fork.parent).or_default();
+                    children.push_back(fork.child);
+                }
+                // threads the recording does not number are numbered after those it does
+                let next_id = replay.forks.iter().map(|fork| fork.child + 1).max();
+                threads
+                    .next_id
+                    .store(next_id.unwrap_or(1).max(1), Ordering::Relaxed);
             }
         }
-        println!();
-        ans
+        let result = match state.main_thread.run_thread() {
+            Err(error) => {
+                threads.stop();
+                Err(error)
+            }
+            Ok(()) => {
+                // the main thread also stops early if every thread is found waiting on an empty
+                // channel
+                threads.finished();
+                // reading back drains the channels, so it must wait until no thread can pop from
+                // them
+                threads.wait(options.exit).and_then(|()| {
+                    let memory = &mut state.main_thread.memory;
+                    match threads.interruption() {
+                        None => Ok(memory.readback()),
+                        Some(Interruption::TimedOut) => Err(MachineError::TimedOut {
+                            timeout: options.timeout.unwrap_or_default(),
+                            memory: memory.drain(),
+                        }),
+                        Some(Interruption::Cancelled) => Err(MachineError::Cancelled {
+                            memory: memory.drain(),
+                        }),
+                    }
+                })
+            }
+        };
+        let recording = threads.live.lock().unwrap().recording.take();
+        (result, recording.unwrap_or_default())
     }
 }
 
@@ -518,19 +1537,72 @@ impl fmt::Display for FcmcProgramState {
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::fcmc::{FcmcProgramState, FcmcTerm, Memory};
+    use crate::error::MachineError;
+    use crate::fcmc::{
+        CancelToken, ExitPolicy, FcmcClosure, FcmcProgramState, FcmcTerm, LocationKind, Locations,
+        Memory, Outcome, RunOptions,
+    };
+    use crate::sink::Sink;
+    use std::thread;
+    use std::time::Duration;
+
+    fn evaluate(term: &str, exit: ExitPolicy) -> Outcome {
+        let term: FcmcTerm = term.parse().unwrap();
+        let locations = Locations::infer(&term);
+        let options = RunOptions {
+            exit,
+            ..RunOptions::default()
+        };
+        FcmcProgramState::evaluate(term, locations, &options)
+    }
 
     #[test]
     fn prints_term() {
         let term = FcmcTerm::term1();
-        let s = FcmcProgramState::start(term.clone(), Memory::new(term.channel_scan()))
+        let s = FcmcProgramState::start(term.clone(), Memory::new(Locations::infer(&term)))
             .main_thread
             .closure
             .term;
         assert_eq!(s.to_string(), "{[[x]~out]~a}.~a<y>.y");
     }
 
+    #[test]
+    fn infers_locations() {
+        let locations = Locations::infer(&FcmcTerm::term1());
+        assert_eq!(locations.kind("~a"), Some(LocationKind::Channel));
+        assert_eq!(locations.kind("~out"), Some(LocationKind::Channel));
+        let locations = Locations::infer(&FcmcTerm::term2());
+        assert_eq!(locations.kind("out"), Some(LocationKind::Stack));
+        assert_eq!(locations.kind("c"), None);
+    }
+
+    #[test]
+    fn declared_channel() {
+        let term = FcmcTerm::new_fork(
+            FcmcTerm::new_push(FcmcTerm::new_variable("x"), "a".to_string(), FcmcTerm::Skip),
+            FcmcTerm::new_pop(
+                "a".to_string(),
+                "y",
+                FcmcTerm::new_push(
+                    FcmcTerm::new_variable("y"),
+                    "out".to_string(),
+                    FcmcTerm::Skip,
+                ),
+            ),
+        );
+        let mut locations = Locations::new();
+        locations
+            .declare("a".to_string(), LocationKind::Channel)
+            .unwrap();
+        locations
+            .declare("out".to_string(), LocationKind::Stack)
+            .unwrap();
+        let ans = FcmcProgramState::run_with_locations(term, locations, &Sink::None).unwrap();
+        assert_eq!(ans, vec![("out".to_string(), FcmcTerm::new_variable("x"))]);
+    }
+
     #[test]
     fn spawn_thread() {
         let fork_term = FcmcTerm::new_fork(
@@ -541,28 +1613,250 @@ mod tests {
             ),
             FcmcTerm::new_pop("~a".to_string(), "y", FcmcTerm::Skip),
         );
-        let ans = FcmcProgramState::run(fork_term);
+        let ans = FcmcProgramState::run(fork_term).unwrap();
         assert_eq!(ans, vec![]);
     }
 
     #[test]
     fn run_term1() {
-        let ans = FcmcProgramState::run(FcmcTerm::term1());
+        let ans = FcmcProgramState::run(FcmcTerm::term1()).unwrap();
         let expected = ("~out".to_string(), FcmcTerm::new_variable("x"));
         assert_eq!(ans, vec![expected]);
     }
 
     #[test]
     fn run_term2() {
-        let ans = FcmcProgramState::run(FcmcTerm::term2());
+        let ans = FcmcProgramState::run(FcmcTerm::term2()).unwrap();
         let expected = ("out".to_string(), FcmcTerm::new_variable("z"));
         assert_eq!(ans, vec![expected]);
     }
 
     #[test]
     fn run_term3() {
-        let ans = FcmcProgramState::run(FcmcTerm::term3());
+        let ans = FcmcProgramState::run(FcmcTerm::term3()).unwrap();
         let expected = ("~out".to_string(), FcmcTerm::new_variable("x"));
         assert_eq!(ans, vec![expected]);
     }
+
+    #[test]
+    fn reports_unbound_variable() {
+        let ans = FcmcProgramState::run(FcmcTerm::new_variable("x"));
+        let expected = MachineError::UnboundVariable {
+            name: "x".to_string(),
+            closure: FcmcClosure::new(FcmcTerm::new_variable("x"), vec![]),
+        };
+        assert_eq!(ans, Err(expected));
+    }
+
+    #[test]
+    fn reports_empty_stack() {
+        let term: FcmcTerm = "[x]a.b<y>.y".parse().unwrap();
+        match FcmcProgramState::run(term) {
+            Err(MachineError::EmptyLocation { location, closure }) => {
+                assert_eq!(location, "b");
+                assert_eq!(closure.term.to_string(), "b<y>.y");
+            }
+            ans => panic!("expected an empty location, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn reports_unknown_location() {
+        let term: FcmcTerm = "[x]a.b<y>.y".parse().unwrap();
+        let ans = FcmcProgramState::run_with_locations(term, Locations::new(), &Sink::None);
+        match ans {
+            Err(MachineError::UnknownLocation { location, closure }) => {
+                assert_eq!(location, "b");
+                assert_eq!(closure.term.to_string(), "b<y>.y");
+            }
+            ans => panic!("expected an unknown location, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn reports_failed_thread() {
+        let term: FcmcTerm = "{z}.[x]a".parse().unwrap();
+        let forked = FcmcClosure::new(FcmcTerm::new_variable("z"), vec![]);
+        let expected = MachineError::InThread {
+            thread: 1,
+            forked: forked.clone(),
+            error: box MachineError::UnboundVariable {
+                name: "z".to_string(),
+                closure: forked,
+            },
+        };
+        assert_eq!(FcmcProgramState::run(term), Err(expected));
+    }
+
+    #[test]
+    fn reports_failed_nested_thread() {
+        let term: FcmcTerm = "{[x]~a.{~b<y>.y}.[y]~b}.~a<x>.*".parse().unwrap();
+        match FcmcProgramState::run(term) {
+            Err(MachineError::InThread { thread, error, .. }) => {
+                assert_eq!(thread, 2);
+                assert!(matches!(*error, MachineError::UnboundVariable { .. }));
+            }
+            ans => panic!("expected a failed thread, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn waits_for_forked_threads() {
+        let ans = evaluate("{[x]~out}.*", ExitPolicy::WaitForAll).unwrap();
+        assert_eq!(ans, vec![("~out".to_string(), FcmcTerm::new_variable("x"))]);
+    }
+
+    #[test]
+    fn reports_blocked_threads() {
+        let ans = evaluate("{~a<x>.[x]~out}.*", ExitPolicy::WaitForAll);
+        let closure = FcmcClosure::new("~a<x>.[x]~out".parse().unwrap(), vec![]);
+        let expected = MachineError::Deadlock {
+            blocked: vec![(1, "~a".to_string(), closure)],
+        };
+        assert_eq!(ans, Err(expected));
+    }
+
+    #[test]
+    fn terminates_forked_threads() {
+        let ans = evaluate("{~a<x>.[x]~out}.*", ExitPolicy::MainExitTerminates).unwrap();
+        assert_eq!(ans, vec![]);
+    }
+
+    #[test]
+    fn detects_deadlock() {
+        let ans = evaluate("{~a<x>.[x]~b}.~b<y>.[y]~a", ExitPolicy::MainExitTerminates);
+        match ans {
+            Err(MachineError::Deadlock { blocked }) => {
+                let waiting: Vec<(usize, &str, String)> = blocked
+                    .iter()
+                    .map(|(id, location, closure)| {
+                        (*id, location.as_str(), closure.term.to_string())
+                    })
+                    .collect();
+                assert_eq!(
+                    waiting,
+                    vec![
+                        (0, "~b", "~b<y>.[y]~a".to_string()),
+                        (1, "~a", "~a<x>.[x]~b".to_string())
+                    ]
+                );
+            }
+            ans => panic!("expected a deadlock, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn detects_deadlock_in_main_thread() {
+        let ans = evaluate("~a<x>.x", ExitPolicy::WaitForAll);
+        let closure = FcmcClosure::new("~a<x>.x".parse().unwrap(), vec![]);
+        let expected = MachineError::Deadlock {
+            blocked: vec![(0, "~a".to_string(), closure)],
+        };
+        assert_eq!(ans, Err(expected));
+    }
+
+    #[test]
+    fn limits_steps_of_each_thread() {
+        // pops a copy of itself, pushes it back and runs it, forever
+        let omega = "a<x>.[x]a.x";
+        let options = RunOptions {
+            fuel: Some(10),
+            ..RunOptions::default()
+        };
+        let term: FcmcTerm = format!("[{}]a.{}", omega, omega).parse().unwrap();
+        let ans = FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options);
+        assert!(matches!(
+            ans,
+            Err(MachineError::StepLimitExceeded { limit: 10, .. })
+        ));
+        let term: FcmcTerm = format!("{{[{}]a.{}}}.*", omega, omega).parse().unwrap();
+        match FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options) {
+            Err(MachineError::InThread { thread, error, .. }) => {
+                assert_eq!(thread, 1);
+                assert!(matches!(*error, MachineError::StepLimitExceeded { .. }));
+            }
+            ans => panic!("expected a thread to run out of fuel, got {:?}", ans),
+        }
+    }
+
+    /// The terms left in each location when a run was stopped
+    fn left(memory: Vec<(String, FcmcClosure)>) -> Vec<(String, String)> {
+        memory
+            .into_iter()
+            .map(|(location, closure)| (location, closure.retrieve_term().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn stops_threads_after_timeout() {
+        // the first thread waits on a channel while the second runs forever
+        let term: FcmcTerm = "{~a<x>.x}.{[b<x>.[x]b.x]b.b<x>.[x]b.x}.[y]~out.*"
+            .parse()
+            .unwrap();
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..RunOptions::default()
+        };
+        match FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options) {
+            Err(MachineError::TimedOut { timeout, memory }) => {
+                assert_eq!(timeout, Duration::from_millis(50));
+                assert_eq!(left(memory), vec![("~out".to_string(), "y".to_string())]);
+            }
+            ans => panic!("expected a timeout, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn stops_threads_when_cancelled() {
+        let term: FcmcTerm = "{~a<x>.x}.{[b<x>.[x]b.x]b.b<x>.[x]b.x}.[y]~out.*"
+            .parse()
+            .unwrap();
+        let cancel = CancelToken::new();
+        let options = RunOptions {
+            cancel: Some(cancel.clone()),
+            ..RunOptions::default()
+        };
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        match FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options) {
+            Err(MachineError::Cancelled { memory }) => {
+                assert_eq!(left(memory), vec![("~out".to_string(), "y".to_string())]);
+            }
+            ans => panic!("expected the run to be cancelled, got {:?}", ans),
+        }
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn cancelled_token_stops_run_before_it_starts() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let options = RunOptions {
+            cancel: Some(cancel),
+            ..RunOptions::default()
+        };
+        let term: FcmcTerm = "[x]~out.*".parse().unwrap();
+        let ans = FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options);
+        assert_eq!(ans, Err(MachineError::Cancelled { memory: vec![] }));
+    }
+
+    #[test]
+    fn traces_to_sink() {
+        let term: FcmcTerm = "{[x]~out}.*".parse().unwrap();
+        let sink = Sink::buffer();
+        let ans =
+            FcmcProgramState::run_with_locations(term.clone(), Locations::infer(&term), &sink);
+        assert_eq!(
+            ans,
+            Ok(vec![("~out".to_string(), FcmcTerm::new_variable("x"))])
+        );
+        let written = sink.contents().unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        // the forked thread writes alongside the main thread, so only its own lines are in order
+        assert_eq!(lines[0], "{[x]~out}, []");
+        assert!(lines.contains(&"New thread 1 spawned: [x]~out"));
+        assert_eq!(lines[lines.len() - 2..], ["OUTPUT:", "~out: x"]);
+    }
 }
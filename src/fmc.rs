@@ -1,7 +1,12 @@
+use crate::error::{MachineError, ParseError};
 use crate::lambdaterm::Var;
+use crate::machine::{AbstractMachine, Driver};
+use crate::parser::FmcTermParser;
+use crate::sink::Sink;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
+use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum FmcTerm {
@@ -58,6 +63,16 @@ impl FmcTerm {
             next: box next,
         }
     }
+
+    /// Returns true if self prints as a sequence or ends in a prefix that scopes over everything
+    /// to its right, so it needs parentheses on the left of a `;`
+    fn is_open(&self) -> bool {
+        match self {
+            FmcTerm::Pop { next, .. } | FmcTerm::Push { next, .. } => **next != FmcTerm::Skip,
+            FmcTerm::Seq { .. } => true,
+            FmcTerm::Skip | FmcTerm::Variable { .. } => false,
+        }
+    }
 }
 
 impl fmt::Display for FmcTerm {
@@ -81,14 +96,28 @@ impl fmt::Display for FmcTerm {
                 FmcTerm::Skip => write!(f, "[{}]{}", term, location_id),
                 _ => write!(f, "[{}]{}.{}", term, location_id, next),
             },
-            FmcTerm::Seq { term, next } => match **next {
-                FmcTerm::Skip => write!(f, "{}", term),
-                _ => write!(f, "{};{}", term, next),
-            },
+            // the left of a `;` is parenthesised only when it would otherwise extend over the `;`
+            FmcTerm::Seq { term, next } => {
+                if term.is_open() {
+                    write!(f, "({});{}", term, next)
+                } else {
+                    write!(f, "{};{}", term, next)
+                }
+            }
         }
     }
 }
 
+impl FromStr for FmcTerm {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FmcTermParser::new()
+            .parse(s)
+            .map_err(|e| ParseError::new(s, e))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct FmcClosure {
     term: FmcTerm,
@@ -108,7 +137,7 @@ impl FmcClosure {
                     FmcTerm::Variable { name }
                 } else {
                     let env_last = self.env.pop().unwrap();
-                    if &env_last.0 == &name {
+                    if env_last.0 == name {
                         env_last.1.retrieve_term()
                     } else {
                         self.term = FmcTerm::Variable { name };
@@ -185,11 +214,36 @@ impl FmcState {
         }
     }
 
+    /// Run the given ['FmcTerm'] on the functional machine, writing each step and then the output
+    /// to `sink`, and stopping after `fuel` steps if it is set
+    pub fn run_with(
+        term: FmcTerm,
+        fuel: Option<usize>,
+        sink: &Sink,
+    ) -> Result<Vec<(Var, FmcTerm)>, MachineError<FmcClosure>> {
+        let ans = Driver::<FmcState>::new(fuel)
+            .on_state(|s| sink.line(format_args!("{}", s)))
+            .run(term)?;
+        for (n, t) in ans.iter().rev() {
+            sink.line(format_args!("{}: {}", n, t));
+        }
+        Ok(ans)
+    }
+}
+
+impl AbstractMachine for FmcState {
+    type Term = FmcTerm;
+    type Closure = FmcClosure;
+    type Output = Vec<(Var, FmcTerm)>;
+
+    /// Create a start state: ['FmcState'] with empty memory from a term: ['FmcTerm']
     fn start(t: FmcTerm) -> Self {
         FmcState::new(FmcClosure::new(t, vec![]), HashMap::new(), vec![])
     }
 
-    fn step(&mut self) -> Result<(), String> {
+    /// Perform a single step in-place of the abstract machine by mutating ['self'][FmcState]
+    /// returns an error if the term is not a valid program ie. cannot be run
+    fn step(&mut self) -> Result<(), MachineError<FmcClosure>> {
         match self.closure.term.clone() {
             FmcTerm::Skip => {
                 if !self.final_() {
@@ -197,8 +251,15 @@ impl FmcState {
                 }
             }
             FmcTerm::Variable { name } => {
-                let env_last = self.closure.env.pop().unwrap();
-                if name.clone() == env_last.0 {
+                let env_last =
+                    self.closure
+                        .env
+                        .pop()
+                        .ok_or_else(|| MachineError::UnboundVariable {
+                            name: name.clone(),
+                            closure: self.closure.clone(),
+                        })?;
+                if name == env_last.0 {
                     self.closure = env_last.1;
                 }
             }
@@ -207,16 +268,20 @@ impl FmcState {
                 arg,
                 next,
             } => {
-                let location = self
-                    .memory
-                    .get_mut(&location_id)
-                    .ok_or("Specified location doesn't exist".to_string())?;
-                let stack_last = location.pop().ok_or(
-                    "Term cannot be executed. Pop action encountered but location is empty."
-                        .to_string(),
-                )?;
+                let Some(location) = self.memory.get_mut(&location_id) else {
+                    return Err(MachineError::UnknownLocation {
+                        location: location_id,
+                        closure: self.closure.clone(),
+                    });
+                };
+                let Some(stack_last) = location.pop() else {
+                    return Err(MachineError::EmptyLocation {
+                        location: location_id,
+                        closure: self.closure.clone(),
+                    });
+                };
                 self.closure.term = *next;
-                self.closure.env.push((arg.clone(), stack_last.clone()));
+                self.closure.env.push((arg, stack_last));
             }
             FmcTerm::Push {
                 term,
@@ -238,6 +303,7 @@ impl FmcState {
         Ok(())
     }
 
+    /// Given ['self'][FmcState], return the contents of each location as ['FmcTerm']s
     fn readback(&mut self) -> Vec<(Var, FmcTerm)> {
         let mut res = vec![];
         for (name, location) in self.memory.iter() {
@@ -248,6 +314,7 @@ impl FmcState {
         res
     }
 
+    /// Returns true if ['self'][FmcState] is a final state ie. computation is complete
     fn final_(&self) -> bool {
         match self.closure.term {
             FmcTerm::Skip => self.continuation.is_empty(),
@@ -255,28 +322,21 @@ impl FmcState {
         }
     }
 
-    pub fn run(term: FmcTerm) -> Vec<(Var, FmcTerm)> {
-        let mut s = FmcState::start(term);
-        while !s.final_() {
-            println!("{}", s);
-            match s.step() {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error during step: \"{}\"", e);
-                    return vec![];
-                }
+    /// The error for stopping in this state after `limit` steps
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<FmcClosure> {
+        let mut memory = vec![];
+        for (name, location) in self.memory.iter() {
+            for closure in location.iter().rev() {
+                memory.push((name.clone(), closure.clone()));
             }
         }
-        println!("{}", s);
-        let ans = s.clone().readback();
-        let len = ans.len();
-        for (i, (n, t)) in ans.iter().rev().enumerate() {
-            print!("{}: {}", n, t);
-            if i < len - 1 {
-                println!(", ");
-            }
+        MachineError::StepLimitExceeded {
+            limit,
+            closure: self.closure.clone(),
+            stack: vec![],
+            continuation: self.continuation.clone(),
+            memory,
         }
-        ans
     }
 }
 
@@ -321,8 +381,12 @@ impl fmt::Display for FmcState {
     }
 }
 
+#[cfg(test)]
 mod tests {
+    use crate::error::MachineError;
     use crate::fmc::{FmcClosure, FmcState, FmcTerm};
+    use crate::machine::AbstractMachine;
+    use crate::sink::Sink;
 
     #[test]
     fn prints_term() {
@@ -344,15 +408,53 @@ mod tests {
 
     #[test]
     fn run_term1() {
-        let ans = FmcState::run(FmcTerm::term1());
+        let ans = FmcState::run(FmcTerm::term1()).unwrap();
         let expected = ("out".to_string(), FmcTerm::new_variable("x"));
         assert_eq!(ans, vec![expected]);
     }
 
     #[test]
     fn run_term2() {
-        let ans = FmcState::run(FmcTerm::term2());
+        let ans = FmcState::run(FmcTerm::term2()).unwrap();
         let expected = ("out".to_string(), FmcTerm::new_variable("z"));
         assert_eq!(ans, vec![expected]);
     }
+
+    #[test]
+    fn reports_unknown_location() {
+        let term: FmcTerm = "a<x>.x".parse().unwrap();
+        let expected = MachineError::UnknownLocation {
+            location: "a".to_string(),
+            closure: FmcClosure::new(term.clone(), vec![]),
+        };
+        assert_eq!(FmcState::run(term), Err(expected));
+    }
+
+    #[test]
+    fn reports_empty_location() {
+        let term: FmcTerm = "[x]a;a<y>.a<z>.z".parse().unwrap();
+        match FmcState::run(term) {
+            Err(MachineError::EmptyLocation { location, .. }) => assert_eq!(location, "a"),
+            ans => panic!("expected an empty location, got {:?}", ans),
+        }
+    }
+
+    #[test]
+    fn keeps_memory_without_fuel() {
+        // pops a copy of itself, pushes it back and runs it, forever
+        let omega = "a<x>.[x]a.x";
+        let term: FmcTerm = format!("[y]b.[{}]a.{}", omega, omega).parse().unwrap();
+        match FmcState::run_with(term, Some(10), &Sink::None) {
+            Err(MachineError::StepLimitExceeded { limit, memory, .. }) => {
+                assert_eq!(limit, 10);
+                let b: Vec<FmcTerm> = memory
+                    .into_iter()
+                    .filter(|(name, _)| name == "b")
+                    .map(|(_, closure)| closure.retrieve_term())
+                    .collect();
+                assert_eq!(b, vec![FmcTerm::new_variable("y")]);
+            }
+            ans => panic!("expected to run out of fuel, got {:?}", ans),
+        }
+    }
 }
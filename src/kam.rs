@@ -1,5 +1,7 @@
-use crate::lambdaterm::Var;
-use crate::LambdaTerm;
+use crate::error::MachineError;
+use crate::lambdaterm::{LambdaTerm, Var};
+use crate::machine::{AbstractMachine, Driver};
+use crate::sink::Sink;
 use std::fmt;
 use std::fmt::Formatter;
 
@@ -23,7 +25,7 @@ impl Closure {
                     LambdaTerm::Variable { name }
                 } else {
                     let env_last = self.env.pop().expect("");
-                    if &env_last.0 == &name {
+                    if env_last.0 == name {
                         env_last.1.retrieve_term()
                     } else {
                         self.term = LambdaTerm::Variable { name };
@@ -75,33 +77,56 @@ impl State {
         State { closure, stack }
     }
 
+    /// Run the given ['LambdaTerm'] on the krivine abstract machine, writing each step and the
+    /// output to `sink`. A lambda term never gets stuck, so this only fails with
+    /// [`StepLimitExceeded`][MachineError::StepLimitExceeded], once `fuel` steps are taken if it
+    /// is set
+    pub fn run_with(
+        term: LambdaTerm,
+        fuel: Option<usize>,
+        sink: &Sink,
+    ) -> Result<LambdaTerm, MachineError<Closure>> {
+        let ans = Driver::<State>::new(fuel)
+            .on_state(|s| sink.line(format_args!("{}", s)))
+            .run(term)?;
+        sink.line(format_args!("{}", ans));
+        Ok(ans)
+    }
+}
+
+impl AbstractMachine for State {
+    type Term = LambdaTerm;
+    type Closure = Closure;
+    type Output = LambdaTerm;
+
     /// Create a start state: ['State'] with an empty stack from a term: ['LambdaTerm']
     fn start(t: LambdaTerm) -> State {
         State::new(Closure::new(t, vec![]), vec![])
     }
 
     /// Perform a single step in-place of the abstract machine by mutating ['self'][State]
-    fn step(&mut self) {
+    fn step(&mut self) -> Result<(), MachineError<Closure>> {
         if self.final_() {
-            return;
+            return Ok(());
         }
         match self.closure.term.clone() {
             LambdaTerm::Variable { name } => {
                 let env_last = self.closure.env.pop().expect("");
-                if name.clone() == env_last.0 {
+                if name == env_last.0 {
                     self.closure = env_last.1;
                 }
             }
             LambdaTerm::Lambda { arg, body } => {
                 let stack_last = self.stack.pop().expect("");
                 self.closure.term = *body;
-                self.closure.env.push((arg.clone(), stack_last.clone()))
+                self.closure.env.push((arg, stack_last))
             }
             LambdaTerm::Apply { t1, t2 } => {
                 self.closure.term = *t1;
                 self.stack.push(Closure::new(*t2, self.closure.env.clone()))
             }
         }
+        Ok(())
     }
 
     /// Given ['self'][State], return the equivalent ['LambdaTerm']
@@ -127,17 +152,15 @@ impl State {
         }
     }
 
-    /// Run the given ['LambdaTerm'] on the krivine abstract machine, printing each step and the output
-    pub fn run(term: LambdaTerm) -> LambdaTerm {
-        let mut s = State::start(term);
-        while !s.final_() {
-            println!("{}", s);
-            s.step();
+    /// The error for stopping in this state after `limit` steps
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<Closure> {
+        MachineError::StepLimitExceeded {
+            limit,
+            closure: self.closure.clone(),
+            stack: self.stack.clone(),
+            continuation: vec![],
+            memory: vec![],
         }
-        println!("{}", s);
-        let ans = s.clone().readback();
-        println!("{}", ans);
-        ans
     }
 }
 
@@ -164,6 +187,8 @@ impl fmt::Display for State {
 mod tests {
     use crate::kam::{Closure, State};
     use crate::lambdaterm::LambdaTerm;
+    use crate::machine::AbstractMachine;
+    use crate::sink::Sink;
 
     #[test]
     fn prints_state() {
@@ -422,7 +447,7 @@ mod tests {
             ),
             vec![],
         );
-        step0.step();
+        step0.step().unwrap();
         let step1 = State::new(
             Closure::new(
                 LambdaTerm::Lambda {
@@ -481,7 +506,7 @@ mod tests {
                 )],
             )],
         );
-        step0.step();
+        step0.step().unwrap();
         let step1 = State::new(
             Closure::new(
                 LambdaTerm::Lambda {
@@ -527,13 +552,13 @@ mod tests {
 
     #[test]
     fn run_term1() {
-        let s = State::run(LambdaTerm::term1());
+        let s = State::run(LambdaTerm::term1()).unwrap();
         assert_eq!(LambdaTerm::new_bool(true).to_string(), s.to_string());
     }
 
     #[test]
     fn run_term2() {
-        let s = State::run(LambdaTerm::term2());
+        let s = State::run(LambdaTerm::term2()).unwrap();
         let s2 = LambdaTerm::Lambda {
             arg: "b".to_string(),
             body: box LambdaTerm::Lambda {
@@ -543,4 +568,17 @@ mod tests {
         };
         assert_eq!(s, s2);
     }
+
+    #[test]
+    fn writes_steps_to_sink() {
+        let term: LambdaTerm = r"(\x. x) y".parse().unwrap();
+        let sink = Sink::buffer();
+        let ans = State::run_with(term, None, &sink).unwrap();
+        let written = sink.contents().unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        // every state from the start to the final one, then the output
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3], State::start(ans).to_string());
+        assert_eq!(lines[4], "y");
+    }
 }
@@ -1,6 +1,9 @@
+use crate::error::ParseError;
+use crate::parser::LambdaTermParser;
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Formatter;
+use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum LambdaTerm {
@@ -81,7 +84,7 @@ impl LambdaTerm {
                 return var;
             }
         }
-        for (c, i) in ('a'..='z').into_iter().cycle().zip(1..) {
+        for (c, i) in ('a'..='z').cycle().zip(1..) {
             let var = format!("{}{}", c, i);
             if !used.contains(&var) {
                 return var;
@@ -196,6 +199,16 @@ impl fmt::Display for LambdaTerm {
     }
 }
 
+impl FromStr for LambdaTerm {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LambdaTermParser::new()
+            .parse(s)
+            .map_err(|e| ParseError::new(s, e))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::LambdaTerm;
@@ -0,0 +1,79 @@
+//! The Functional Concurrent Machine Calculus, and the abstract machines it builds on.
+//!
+//! Each calculus has a term type that can be parsed from text with [`str::parse`] and printed
+//! back with [`Display`][std::fmt::Display], and a machine that runs it:
+//!
+//! | Calculus                   | Term                      | Machine                         |
+//! |----------------------------|---------------------------|---------------------------------|
+//! | lambda calculus            | [`LambdaTerm`]            | [`PState::run`], [`State::run`] |
+//! | sequential lambda calculus | [`SLambdaTerm`]           | [`SState::run`]                 |
+//! | FMC                        | [`FmcTerm`]               | [`FmcState::run`]               |
+//! | FCMC                       | [`FcmcTerm`], [`Program`] | [`FcmcProgramState::evaluate`]  |
+//!
+//! The `run` functions return the output of a machine, or a [`MachineError`] if the term gets
+//! stuck, and print nothing; the `run_with` functions write each state and the output to a
+//! [`Sink`] instead, such as standard output or a buffer to read back.
+//! The machines can also be driven one step at a time through their `start`, `step`, `final_` and
+//! `readback` functions, which every machine shares as an [`AbstractMachine`], and which a
+//! [`Driver`] uses to run any of them with a step limit and a hook on each state. The FCMC
+//! machine is driven this way as a [`Scheduler`]. FCMC source files with
+//! imports, declarations and definitions are loaded with [`Program::load`] and turned into a
+//! term to run with [`Program::expand`] and [`Program::locations`]. The threads of an FCMC term
+//! run concurrently by default, or can be interleaved reproducibly by a [`Scheduler`], and
+//! [`explore`] finds the outcome of every interleaving, or [`reachable_outcomes`] the outcomes
+//! alone while skipping interleavings that only reorder commuting steps.
+//!
+//! ```
+//! use fcmc::{FcmcProgramState, Program, RunOptions};
+//!
+//! let program: Program = "def Send = [[x]~out]~a;\n{Send}.~a<y>.y".parse().unwrap();
+//! let term = program.expand().unwrap();
+//! let locations = program.locations(&term).unwrap();
+//! let options = RunOptions::default();
+//! let output = FcmcProgramState::evaluate(term, locations, &options).unwrap();
+//! assert_eq!(output[0].0, "~out");
+//! assert_eq!(output[0].1.to_string(), "x");
+//! ```
+#![feature(box_patterns, box_syntax)]
+#![allow(clippy::uninlined_format_args, clippy::bool_assert_comparison)]
+extern crate lalrpop_util;
+
+use lalrpop_util::lalrpop_mod;
+
+pub mod error;
+pub mod examples;
+pub mod explorer;
+pub mod fcmc;
+pub mod fmc;
+pub mod kam;
+pub mod lambdaterm;
+pub mod machine;
+pub mod pam;
+mod parser_test;
+pub mod program;
+pub mod recording;
+pub mod sam;
+pub mod scheduler;
+pub mod sink;
+
+lalrpop_mod!(#[allow(clippy::all)] pub parser);
+
+pub use crate::error::{
+    DefinitionError, LocationError, MachineError, ParseError, RecordingError, SourceError,
+    SourceErrorKind,
+};
+pub use crate::explorer::{explore, reachable_outcomes, Exploration};
+pub use crate::fcmc::{
+    CancelToken, ExitPolicy, FcmcProgramState, FcmcTerm, LocationKind, Locations, Outcome,
+    RunOptions, Schedule,
+};
+pub use crate::fmc::{FmcState, FmcTerm};
+pub use crate::kam::State;
+pub use crate::lambdaterm::{LambdaTerm, Var};
+pub use crate::machine::{AbstractMachine, Driver};
+pub use crate::pam::PState;
+pub use crate::program::Program;
+pub use crate::recording::{Access, Event, Fork, Recording};
+pub use crate::sam::{SLambdaTerm, SState};
+pub use crate::scheduler::{Policy, Random, RoundRobin, Scheduler};
+pub use crate::sink::Sink;
@@ -0,0 +1,150 @@
+use crate::error::MachineError;
+
+/// An abstract machine that runs a term one transition at a time. Each machine implements this
+/// alongside functions of its own, so that tools driving a machine step by step can be written
+/// once for all of them
+pub trait AbstractMachine: Sized {
+    /// The terms the machine runs
+    type Term;
+    /// The closures the machine evaluates, which its errors carry
+    type Closure;
+    /// What is read back from the machine once it has finished
+    type Output;
+
+    /// The state the machine starts in to run `term`
+    fn start(term: Self::Term) -> Self;
+
+    /// Perform a single transition, or fail if the term gets stuck
+    fn step(&mut self) -> Result<(), MachineError<Self::Closure>>;
+
+    /// Returns true once the machine has finished running its term
+    fn final_(&self) -> bool;
+
+    /// Read back the output of the machine from its state
+    fn readback(&mut self) -> Self::Output;
+
+    /// The error for stopping in this state after `limit` steps
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<Self::Closure>;
+
+    /// Run `term` to completion without printing anything
+    fn run(term: Self::Term) -> Result<Self::Output, MachineError<Self::Closure>> {
+        Driver::<Self>::new(None).run(term)
+    }
+}
+
+/// A function called with each state a [`Driver`] steps a machine through
+type Hook<'a, M> = Box<dyn FnMut(&M) + 'a>;
+
+/// Steps an [`AbstractMachine`] until it finishes, counting its steps, stopping it once it has
+/// taken as many as its fuel allows, and calling a hook with every state it passes through
+pub struct Driver<'a, M> {
+    /// The number of steps the machine may take, if they are limited
+    fuel: Option<usize>,
+    /// The number of steps taken so far
+    steps: usize,
+    /// Called with every state of the machine
+    hook: Option<Hook<'a, M>>,
+}
+
+impl<'a, M: AbstractMachine> Driver<'a, M> {
+    /// Create a [`Driver`] that stops the machine after `fuel` steps if it is set
+    pub fn new(fuel: Option<usize>) -> Self {
+        Driver {
+            fuel,
+            steps: 0,
+            hook: None,
+        }
+    }
+
+    /// Call `hook` with each state of the machine before it steps, and with its final state
+    pub fn on_state(mut self, hook: impl FnMut(&M) + 'a) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// The number of steps the machine has taken, over every run of this driver
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Step `machine` until it has finished
+    pub fn drive(&mut self, machine: &mut M) -> Result<(), MachineError<M::Closure>> {
+        let mut steps = 0;
+        loop {
+            if let Some(hook) = &mut self.hook {
+                hook(machine);
+            }
+            if machine.final_() {
+                return Ok(());
+            }
+            if self.fuel == Some(steps) {
+                return Err(machine.step_limit_exceeded(steps));
+            }
+            machine.step()?;
+            steps += 1;
+            self.steps += 1;
+        }
+    }
+
+    /// Run `term` from the start state of the machine, returning its output
+    pub fn run(&mut self, term: M::Term) -> Result<M::Output, MachineError<M::Closure>> {
+        let mut machine = M::start(term);
+        self.drive(&mut machine)?;
+        Ok(machine.readback())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MachineError;
+    use crate::fcmc::FcmcTerm;
+    use crate::kam::State;
+    use crate::lambdaterm::LambdaTerm;
+    use crate::machine::{AbstractMachine, Driver};
+    use crate::pam::PState;
+    use crate::scheduler::{RoundRobin, Scheduler};
+
+    #[test]
+    fn lambda_machines_agree() {
+        for term in [r"(\x. x) y", r"(\x. \y. x) a b", r"(\f. f (f z)) (\x. x)"] {
+            let term: LambdaTerm = term.parse().unwrap();
+            let pam = PState::run(term.clone()).unwrap();
+            let kam = State::run(term).unwrap();
+            assert_eq!(pam, kam);
+        }
+    }
+
+    #[test]
+    fn counts_steps() {
+        let term: LambdaTerm = r"(\x. x) y".parse().unwrap();
+        let mut states = vec![];
+        let mut driver = Driver::<PState>::new(None).on_state(|s| states.push(s.to_string()));
+        let ans = driver.run(term).unwrap();
+        // the application is unwound, then the abstraction takes its argument
+        assert_eq!(driver.steps(), 2);
+        drop(driver);
+        assert_eq!(states.len(), 3);
+        assert_eq!(ans, LambdaTerm::new_var("y"));
+    }
+
+    #[test]
+    fn stops_without_fuel() {
+        let omega: LambdaTerm = r"(\x. x x) (\x. x x)".parse().unwrap();
+        let ans = Driver::<State>::new(Some(20)).run(omega);
+        assert!(matches!(
+            ans,
+            Err(MachineError::StepLimitExceeded { limit: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn drives_fcmc_threads() {
+        let term: FcmcTerm = "{[x]~out}.~out<y>.[y]a".parse().unwrap();
+        let ans = Scheduler::<RoundRobin>::run(term).unwrap();
+        assert_eq!(ans, vec![("a".to_string(), FcmcTerm::new_variable("x"))]);
+        // a forked thread that fails is reported once the others have finished
+        let term: FcmcTerm = "{z}.[x]a".parse().unwrap();
+        let ans = Scheduler::<RoundRobin>::run(term);
+        assert!(matches!(ans, Err(MachineError::InThread { thread: 1, .. })));
+    }
+}
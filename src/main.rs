@@ -1,79 +1,138 @@
-#![feature(box_patterns, box_syntax)]
-extern crate lalrpop_util;
+#![allow(clippy::uninlined_format_args)]
 
-use crate::fcmc::{FcmcProgramState, FcmcTerm};
-use crate::lambdaterm::LambdaTerm;
-use crate::pam::PState;
-use crate::parser::FcmcTermParser;
-use clap::Parser;
-use lalrpop_util::lalrpop_mod;
-use std::io;
+use crate::cli::{Exit, Machine, Scheduling};
+use crate::repl::Repl;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use fcmc::{RunOptions, Sink};
+use std::process::ExitCode;
+use std::time::Duration;
 
-pub mod examples;
-pub mod fcmc;
-pub mod fmc;
-pub mod kam;
-pub mod lambdaterm;
-pub mod pam;
-pub mod parser_test;
-pub mod sam;
-
-lalrpop_mod!(pub parser);
+mod cli;
+mod repl;
 
+/// Run terms on the FCMC abstract machine and the machines it builds on.
+/// Without a subcommand, an interactive session is started
 #[derive(Parser)]
+#[command(
+    version,
+    after_help = "Exit status is 0 on success, 1 if the input cannot be read, parsed or checked, \
+                  2 for invalid arguments and 3 if the machine fails while running the term."
+)]
 struct Args {
-    /// Raw term to run
-    input: String,
-}
-
-pub fn run_parser() {
-    println!("\nInput a term: ");
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read input");
-    let parser = FcmcTermParser::new();
-    let parsed: FcmcTerm = parser.parse(&input.trim()).expect("");
-    println!("\nINPUT: {}", parsed);
-    FcmcProgramState::run(parsed);
-}
-
-pub fn run_example() {
-    println!("\n{}", FcmcTerm::term1());
-    FcmcProgramState::run(FcmcTerm::term1());
-    println!("");
+    /// The machine to run terms on
+    #[arg(short, long, value_enum, default_value_t = Machine::Fcmc, global = true)]
+    machine: Machine,
+    /// What happens to forked threads still running when the main thread of an fcmc term finishes
+    #[arg(long, value_enum, default_value_t = Exit::Wait, global = true)]
+    exit: Exit,
+    /// How the threads of an fcmc term take turns
+    #[arg(long, value_enum, default_value_t = Scheduling::Threads, global = true)]
+    schedule: Scheduling,
+    /// The seed for `--schedule random`; the same seed always gives the same run
+    #[arg(long, default_value_t = 0, global = true)]
+    seed: u64,
+    /// Write the order the threads of an fcmc term take their channel steps in to this file
+    #[arg(long, value_name = "FILE", global = true)]
+    record: Option<String>,
+    /// Take the channel steps of an fcmc term in the order recorded in this file
+    #[arg(long, value_name = "FILE", global = true)]
+    replay: Option<String>,
+    /// Stop with an error after this many steps, or this many steps of any one fcmc thread
+    #[arg(long, value_name = "STEPS", global = true)]
+    fuel: Option<usize>,
+    /// Stop the threads of an fcmc term with an error if they are still running after this many
+    /// milliseconds
+    #[arg(long, value_name = "MILLISECONDS", global = true)]
+    timeout: Option<u64>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-pub fn print_help() {
-    println!("\nTo see an example of a term, choose option 2 in the menu.");
-    println!("When you run a term, it will print out the term and then run it.");
-    println!("Any time a term is forked, the term on the new thread will be printed.");
-    println!("The output of the term will be printed as a list of locations and their contents.");
-    println!("");
+#[derive(Subcommand)]
+enum Command {
+    /// Run a term and print its output, one line per value
+    Run {
+        /// File to read the term from, or - for standard input
+        input: String,
+    },
+    /// Run a term, printing every state of the machine before its output
+    Trace {
+        /// File to read the term from, or - for standard input
+        input: String,
+    },
+    /// Check that a term can be run, printing nothing if it can
+    Check {
+        /// File to read the term from, or - for standard input
+        input: String,
+    },
+    /// Print a term in the form the parser reads back
+    Fmt {
+        /// File to read the term from, or - for standard input
+        input: String,
+    },
+    /// Start an interactive session
+    Repl,
 }
 
-fn main() {
-    loop {
-        println!(
-            "\nWelcome to the FCMC abstract machine.\n\
-        1. Run an FCMC term\n\
-        2. Run an example term\n\
-        3. Help\n\
-        4. Exit\n\
-        [Please choose an option] "
-        );
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read input");
-        match &*input.trim() {
-            "1" => run_parser(),
-            "2" => run_example(),
-            "3" => print_help(),
-            "4" => break,
-            _ => {
-                println!("Invalid choice. Input a number from 1 to 4.")
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if args.machine != Machine::Fcmc {
+        for (flag, set) in [
+            ("--record", args.record.is_some()),
+            ("--replay", args.replay.is_some()),
+            ("--timeout", args.timeout.is_some()),
+        ] {
+            if set {
+                let message = format!("{} can only be used with --machine fcmc", flag);
+                Args::command()
+                    .error(ErrorKind::ArgumentConflict, message)
+                    .exit();
             }
         }
     }
+    if args.schedule != Scheduling::Threads {
+        // recordings are of threads running on OS threads, which the other schedules do not use
+        for (flag, set) in [
+            ("--record", args.record.is_some()),
+            ("--replay", args.replay.is_some()),
+        ] {
+            if set {
+                let message = format!("{} can only be used with --schedule threads", flag);
+                Args::command()
+                    .error(ErrorKind::ArgumentConflict, message)
+                    .exit();
+            }
+        }
+    }
+    let replay = match args.replay.as_deref().map(cli::read_recording).transpose() {
+        Ok(replay) => replay,
+        Err(failure) => return cli::report(Err(failure)),
+    };
+    let options = |trace| RunOptions {
+        trace: if trace { Sink::Stdout } else { Sink::None },
+        exit: args.exit.into(),
+        schedule: args.schedule.with_seed(args.seed),
+        replay: replay.clone(),
+        fuel: args.fuel,
+        timeout: args.timeout.map(Duration::from_millis),
+        cancel: None,
+    };
+    let run = |input: &str, options: &RunOptions| match &args.record {
+        Some(record) => cli::run_recorded(input, options, record),
+        None => cli::run(args.machine, input, options),
+    };
+    match args.command {
+        Some(Command::Run { input }) => run(&input, &options(false)),
+        Some(Command::Trace { input }) => run(&input, &options(true)),
+        Some(Command::Check { input }) => cli::check(args.machine, &input),
+        Some(Command::Fmt { input }) => cli::fmt(args.machine, &input),
+        Some(Command::Repl) | None => match Repl::new(args.machine, options(false)).run() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+    }
 }
@@ -1,4 +1,7 @@
+use crate::error::MachineError;
 use crate::lambdaterm::LambdaTerm;
+use crate::machine::{AbstractMachine, Driver};
+use crate::sink::Sink;
 use std::fmt;
 use std::fmt::Formatter;
 
@@ -14,8 +17,34 @@ impl PState {
         PState { term, stack }
     }
 
+    /// Run the given ['LambdaTerm'] on the partial abstract machine, writing each step and the
+    /// output to `sink`. Returns [`StepLimitExceeded`][MachineError::StepLimitExceeded] if `fuel`
+    /// is set and the term has not finished after that many steps, and no other error
+    pub fn run_with(
+        term: LambdaTerm,
+        fuel: Option<usize>,
+        sink: &Sink,
+    ) -> Result<LambdaTerm, MachineError<LambdaTerm>> {
+        // the final state is left for its readback to show
+        let ans = Driver::<PState>::new(fuel)
+            .on_state(|s| {
+                if !s.final_() {
+                    sink.line(format_args!("{}", s));
+                }
+            })
+            .run(term)?;
+        sink.line(format_args!("{}", ans));
+        Ok(ans)
+    }
+}
+
+impl AbstractMachine for PState {
+    type Term = LambdaTerm;
+    type Closure = LambdaTerm;
+    type Output = LambdaTerm;
+
     /// Create a start ['PState'] from a term: ['SLambdaTerm'] and a stack: ['Vec<SClosure>']
-    pub fn p_start(n: LambdaTerm) -> Self {
+    fn start(n: LambdaTerm) -> Self {
         PState {
             term: n,
             stack: vec![],
@@ -23,9 +52,9 @@ impl PState {
     }
 
     /// Perform a transition step in-place
-    pub fn p_step(&mut self) {
-        if self.p_final() {
-            return;
+    fn step(&mut self) -> Result<(), MachineError<LambdaTerm>> {
+        if self.final_() {
+            return Ok(());
         }
         match &self.term {
             LambdaTerm::Lambda { arg, body } => {
@@ -43,10 +72,11 @@ impl PState {
                 unreachable!()
             }
         }
+        Ok(())
     }
 
     /// Returns true if ['self'][PState] is a final state ie. computation is complete
-    pub fn p_final(&self) -> bool {
+    fn final_(&self) -> bool {
         match self.term {
             LambdaTerm::Variable { .. } => true,
             LambdaTerm::Lambda { .. } => self.stack.is_empty(),
@@ -54,27 +84,27 @@ impl PState {
         }
     }
 
-    /// Run the given ['LambdaTerm'] on the partial abstract machine, printing each step and the output
-    pub fn p_run(term: LambdaTerm) -> LambdaTerm {
-        /// Given ['self'][PState], return the equivalent ['LambdaTerm']
-        fn p_readback(st: &mut PState) -> LambdaTerm {
-            let mut t = st.term.clone();
-            while !st.stack.is_empty() {
-                t = LambdaTerm::Apply {
-                    t1: box t,
-                    t2: box st.stack.pop().expect(""),
-                }
+    /// Given ['self'][PState], return the equivalent ['LambdaTerm']
+    fn readback(&mut self) -> LambdaTerm {
+        let mut t = self.term.clone();
+        while !self.stack.is_empty() {
+            t = LambdaTerm::Apply {
+                t1: box t,
+                t2: box self.stack.pop().expect(""),
             }
-            t
         }
-        let mut s = PState::p_start(term);
-        while !s.p_final() {
-            println!("{}", s);
-            s.p_step();
+        t
+    }
+
+    /// The error for stopping in this state after `limit` steps
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<LambdaTerm> {
+        MachineError::StepLimitExceeded {
+            limit,
+            closure: self.term.clone(),
+            stack: self.stack.clone(),
+            continuation: vec![],
+            memory: vec![],
         }
-        let ans = p_readback(&mut s.clone());
-        println!("{}", ans);
-        ans
     }
 }
 
@@ -94,6 +124,9 @@ impl fmt::Display for PState {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::MachineError;
+    use crate::machine::AbstractMachine;
+    use crate::sink::Sink;
     use crate::{LambdaTerm, PState};
 
     #[test]
@@ -107,7 +140,7 @@ mod tests {
 
     #[test]
     fn term_into_state() {
-        let created = PState::p_start(LambdaTerm::term1());
+        let created = PState::start(LambdaTerm::term1());
         assert_eq!(
             created.to_string(),
             "(((\\x. \\y. x) (\\a. \\b. a)) (\\a. \\b. b),[*])"
@@ -116,16 +149,16 @@ mod tests {
 
     #[test]
     fn detect_end_state() {
-        assert_eq!(PState::state1().p_final(), false);
-        assert_eq!(PState::p_start(LambdaTerm::term1()).p_final(), false);
-        assert_eq!(PState::state2().p_final(), true);
-        assert_eq!(PState::state3().p_final(), true);
+        assert_eq!(PState::state1().final_(), false);
+        assert_eq!(PState::start(LambdaTerm::term1()).final_(), false);
+        assert_eq!(PState::state2().final_(), true);
+        assert_eq!(PState::state3().final_(), true);
     }
 
     #[test]
     fn run_pam() {
-        let ans = PState::p_run(LambdaTerm::term1());
-        assert_eq!(PState::p_start(ans.clone()).p_final(), true);
+        let ans = PState::run(LambdaTerm::term1()).unwrap();
+        assert_eq!(PState::start(ans.clone()).final_(), true);
         assert_eq!(
             ans,
             LambdaTerm::Lambda {
@@ -137,4 +170,17 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn stops_without_fuel() {
+        let omega: LambdaTerm = "(\\x. x x) (\\x. x x) z".parse().unwrap();
+        match PState::run_with(omega, Some(10), &Sink::None) {
+            Err(MachineError::StepLimitExceeded { limit, stack, .. }) => {
+                assert_eq!(limit, 10);
+                // the argument the loop is applied to is left at the bottom of the stack
+                assert_eq!(stack.first(), Some(&LambdaTerm::new_var("z")));
+            }
+            ans => panic!("expected to run out of fuel, got {:?}", ans),
+        }
+    }
 }
@@ -1,18 +1,73 @@
+#[cfg(test)]
 mod tests {
+    use crate::error::ParseError;
     use crate::fcmc::{FcmcProgramState, FcmcTerm};
+    use crate::fmc::{FmcState, FmcTerm};
+    use crate::kam::State;
+    use crate::lambdaterm::LambdaTerm;
+    use crate::machine::AbstractMachine;
     use crate::parser::FcmcTermParser;
+    use crate::program::Program;
+    use crate::sam::{SLambdaTerm, SState};
+    use std::str::FromStr;
+
+    /// Parse `source`, panicking with the rendered [`ParseError`] if it is rejected
+    fn parse<T: FromStr<Err = ParseError>>(source: &str) -> T {
+        source.parse().unwrap_or_else(|e| panic!("\n{}", e))
+    }
+
+    /// A small xorshift generator, so randomly generated terms are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn choose<'a>(&mut self, options: &[&'a str]) -> &'a str {
+            options[self.next() as usize % options.len()]
+        }
+    }
+
+    /// Generate a random [`FcmcTerm`] no deeper than `depth`
+    fn random_term(rng: &mut Rng, depth: usize) -> FcmcTerm {
+        let vars = ["x", "y", "f", "Msg"];
+        let locations = ["a", "out", "~a", "~t1"];
+        let choice = if depth == 0 {
+            rng.next() % 2
+        } else {
+            rng.next() % 6
+        };
+        match choice {
+            0 => FcmcTerm::Skip,
+            1 => FcmcTerm::new_variable(rng.choose(&vars)),
+            2 => FcmcTerm::new_pop(
+                rng.choose(&locations).to_string(),
+                rng.choose(&vars[..3]),
+                random_term(rng, depth - 1),
+            ),
+            3 => FcmcTerm::new_push(
+                random_term(rng, depth - 1),
+                rng.choose(&locations).to_string(),
+                random_term(rng, depth - 1),
+            ),
+            4 => FcmcTerm::new_seq(random_term(rng, depth - 1), random_term(rng, depth - 1)),
+            _ => FcmcTerm::new_fork(random_term(rng, depth - 1), random_term(rng, depth - 1)),
+        }
+    }
 
     #[test]
     fn term1() {
-        let parser = FcmcTermParser::new();
-        let parsed: FcmcTerm = parser.parse("{[[x]~out]~a}.~a<y>.y").expect("");
+        let parsed: FcmcTerm = parse("{[[x]~out]~a}.~a<y>.y");
         assert_eq!(parsed, FcmcTerm::term1());
     }
 
     #[test]
     fn term2() {
-        let parser = FcmcTermParser::new();
-        let parsed: FcmcTerm = parser.parse("[a<x>.x]b.[[z]out]a.b<f>.f").expect("");
+        let parsed: FcmcTerm = parse("[a<x>.x]b.[[z]out]a.b<f>.f");
         assert_eq!(
             FcmcProgramState::run(parsed),
             FcmcProgramState::run(FcmcTerm::term2())
@@ -21,13 +76,241 @@ mod tests {
 
     #[test]
     fn term3() {
+        let parsed: FcmcTerm =
+            parse("[x]~a.({~a<y>.[[y]~out]~b.[*]~t1}.({~b<z>.z;[*]~t2}.~t1<n>.~t2<m>.*))");
+        assert_eq!(
+            FcmcProgramState::run(parsed),
+            FcmcProgramState::run(FcmcTerm::term3())
+        );
+    }
+
+    #[test]
+    fn term3_display() {
+        let parsed: FcmcTerm = parse(&FcmcTerm::term3().to_string());
+        assert_eq!(
+            FcmcProgramState::run(parsed),
+            FcmcProgramState::run(FcmcTerm::term3())
+        );
+    }
+
+    #[test]
+    fn prefix_scopes_over_seq() {
+        let parsed: FcmcTerm = parse("a<x>.x;y");
+        let expected = FcmcTerm::new_pop(
+            "a".to_string(),
+            "x",
+            FcmcTerm::new_seq(FcmcTerm::new_variable("x"), FcmcTerm::new_variable("y")),
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn seq_is_right_associative() {
+        let parsed: FcmcTerm = parse("x;y;z");
+        let expected = FcmcTerm::new_seq(
+            FcmcTerm::new_variable("x"),
+            FcmcTerm::new_seq(FcmcTerm::new_variable("y"), FcmcTerm::new_variable("z")),
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn full_terms_in_brackets() {
+        let parsed: FcmcTerm = parse("[a<x>.x;y]b.[{[z]~c}.~c<w>.w]~d");
+        let expected = FcmcTerm::new_push(
+            FcmcTerm::new_pop(
+                "a".to_string(),
+                "x",
+                FcmcTerm::new_seq(FcmcTerm::new_variable("x"), FcmcTerm::new_variable("y")),
+            ),
+            "b".to_string(),
+            FcmcTerm::new_push(
+                FcmcTerm::new_fork(
+                    FcmcTerm::new_push(
+                        FcmcTerm::new_variable("z"),
+                        "~c".to_string(),
+                        FcmcTerm::Skip,
+                    ),
+                    FcmcTerm::new_pop("~c".to_string(), "w", FcmcTerm::new_variable("w")),
+                ),
+                "~d".to_string(),
+                FcmcTerm::Skip,
+            ),
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn fork_in_seq() {
+        let parsed: FcmcTerm = parse("{[x]~a};~a<y>.y");
+        let expected = FcmcTerm::new_seq(
+            FcmcTerm::new_fork(
+                FcmcTerm::new_push(
+                    FcmcTerm::new_variable("x"),
+                    "~a".to_string(),
+                    FcmcTerm::Skip,
+                ),
+                FcmcTerm::Skip,
+            ),
+            FcmcTerm::new_pop("~a".to_string(), "y", FcmcTerm::new_variable("y")),
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn round_trip_examples() {
+        for term in [FcmcTerm::term1(), FcmcTerm::term2(), FcmcTerm::term3()] {
+            let parsed: FcmcTerm = parse(&term.to_string());
+            assert_eq!(parsed, term);
+        }
+    }
+
+    #[test]
+    fn round_trip_random_terms() {
+        // building a parser compiles its lexer, so share one across all the terms
         let parser = FcmcTermParser::new();
-        let parsed: FcmcTerm = parser
-            .parse("[x]~a.({~a<y>.[[y]~out]~b.[*]~t1}.({~b<z>.z;[*]~t2}.~t1<n>.~t2<m>.*))")
-            .expect("");
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..2000 {
+            let term = random_term(&mut rng, 5);
+            let printed = term.to_string();
+            let parsed = parser
+                .parse(&printed)
+                .unwrap_or_else(|e| panic!("\n{}", ParseError::new(&printed, e)));
+            assert_eq!(parsed, term, "printed as {}", printed);
+        }
+    }
+
+    #[test]
+    fn minimal_parentheses() {
+        assert_eq!(FcmcTerm::term1().to_string(), "{[[x]~out]~a}.~a<y>.y");
+        let seq: FcmcTerm = parse("(x;y);(a<z>.z);[x]b;{*}");
+        assert_eq!(seq.to_string(), "(x;y);(a<z>.z);[x]b;{*}");
+    }
+
+    #[test]
+    fn source_file() {
+        let parsed: FcmcTerm = parse(include_str!("../programs/term3.fcmc"));
         assert_eq!(
             FcmcProgramState::run(parsed),
             FcmcProgramState::run(FcmcTerm::term3())
         );
     }
+
+    #[test]
+    fn definitions_file() {
+        let program: Program = parse(include_str!("../programs/relay.fcmc"));
+        assert_eq!(
+            FcmcProgramState::run(program.expand().unwrap()),
+            FcmcProgramState::run(FcmcTerm::term3())
+        );
+    }
+
+    #[test]
+    fn comments_and_newlines() {
+        let parsed: FcmcTerm = parse("// a fork\n{\n  [[x]~out]~a -- push\n}.\n~a<y>.y\n");
+        assert_eq!(parsed, FcmcTerm::term1());
+    }
+
+    #[test]
+    fn lambda_term1() {
+        let parsed: LambdaTerm = parse(r"(\x. \y. x) (\a. \b. a) (\a. \b. b)");
+        assert_eq!(parsed, LambdaTerm::term1());
+    }
+
+    #[test]
+    fn lambda_term2() {
+        let parsed: LambdaTerm =
+            parse(r"(\b. (\a. \x. (\y. a) x b) (\a. \b. a)) (\z. z) (\a. \b. b)");
+        assert_eq!(parsed, LambdaTerm::term2());
+        assert_eq!(State::run(parsed), State::run(LambdaTerm::term2()));
+    }
+
+    #[test]
+    fn lambda_round_trip() {
+        for term in [
+            LambdaTerm::term1(),
+            LambdaTerm::term2(),
+            *LambdaTerm::example5(),
+            *LambdaTerm::example7(),
+            LambdaTerm::new_num(3),
+        ] {
+            let parsed: LambdaTerm = parse(&term.to_string());
+            assert_eq!(parsed, term);
+        }
+    }
+
+    #[test]
+    fn seq_term1() {
+        let parsed: SLambdaTerm = parse("<x>.[x].[x]");
+        assert_eq!(parsed, SLambdaTerm::term1());
+    }
+
+    #[test]
+    fn seq_term3() {
+        let parsed: SLambdaTerm = parse("[<x>.[x]].<f>.f;f;f;*");
+        assert_eq!(parsed, SLambdaTerm::term3());
+    }
+
+    #[test]
+    fn seq_term4() {
+        let parsed: SLambdaTerm = parse("[[y].[x]].<z>.z;z");
+        assert_eq!(SState::run(parsed), SState::run(SLambdaTerm::term4()));
+    }
+
+    #[test]
+    fn seq_reparses_display() {
+        for term in [
+            SLambdaTerm::term1(),
+            SLambdaTerm::term2(),
+            SLambdaTerm::term3(),
+            SLambdaTerm::term4(),
+            SLambdaTerm::new_seq(SLambdaTerm::new_variable("x"), SLambdaTerm::Skip),
+            SLambdaTerm::new_seq(
+                SLambdaTerm::new_pop("x", SLambdaTerm::new_variable("y")),
+                SLambdaTerm::new_variable("z"),
+            ),
+            SLambdaTerm::new_seq(
+                SLambdaTerm::new_seq(
+                    SLambdaTerm::new_variable("x"),
+                    SLambdaTerm::new_variable("y"),
+                ),
+                SLambdaTerm::new_push(SLambdaTerm::new_variable("z"), SLambdaTerm::Skip),
+            ),
+        ] {
+            let parsed: SLambdaTerm = parse(&term.to_string());
+            assert_eq!(parsed, term);
+        }
+    }
+
+    #[test]
+    fn fmc_term1() {
+        let parsed: FmcTerm = parse("[[x]out]a;a<y>.y");
+        assert_eq!(parsed, FmcTerm::term1());
+    }
+
+    #[test]
+    fn fmc_term2() {
+        let parsed: FmcTerm = parse("[a<x>.x]b.[[z]out]a.b<f>.f");
+        assert_eq!(FmcState::run(parsed), FmcState::run(FmcTerm::term2()));
+    }
+
+    #[test]
+    fn fmc_reparses_display() {
+        let var = FmcTerm::new_variable;
+        for term in [
+            FmcTerm::term1(),
+            FmcTerm::term2(),
+            FmcTerm::new_seq(var("x"), FmcTerm::Skip),
+            FmcTerm::new_seq(FmcTerm::new_pop("a".to_string(), "x", var("y")), var("z")),
+            FmcTerm::new_seq(FmcTerm::new_seq(var("x"), var("y")), var("z")),
+        ] {
+            let parsed: FmcTerm = parse(&term.to_string());
+            assert_eq!(parsed, term);
+        }
+    }
+
+    #[test]
+    fn fmc_rejects_fork() {
+        assert!("{[[x]out]a}.a<y>.y".parse::<FmcTerm>().is_err());
+    }
 }
@@ -0,0 +1,534 @@
+use crate::error::{DefinitionError, LocationError, ParseError, SourceError, SourceErrorKind};
+use crate::fcmc::{FcmcTerm, LocationKind, Locations};
+use crate::lambdaterm::Var;
+use crate::parser::ProgramParser;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs};
+
+/// An FCMC source file: a list of imported files, location declarations and named definitions,
+/// followed by the term to run.
+/// Definition names start with a capital letter, so they can never be bound by a pop
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    imports: Vec<String>,
+    declarations: Vec<(Var, LocationKind)>,
+    definitions: Vec<(Var, FcmcTerm)>,
+    main: Option<FcmcTerm>,
+}
+
+impl Program {
+    /// Create a new ['Program'] from its imports, declarations, definitions and main term
+    pub fn new(
+        imports: Vec<String>,
+        declarations: Vec<(Var, LocationKind)>,
+        definitions: Vec<(Var, FcmcTerm)>,
+        main: Option<FcmcTerm>,
+    ) -> Self {
+        Program {
+            imports,
+            declarations,
+            definitions,
+            main,
+        }
+    }
+
+    /// Load the program in the file at `path`, along with the definitions of every file it
+    /// imports. Imports are resolved relative to the importing file, and each file is read once
+    pub fn load(path: &Path) -> Result<Self, SourceError> {
+        let mut loader = Loader::default();
+        let main = loader.load(path, false)?;
+        Ok(Program::new(
+            vec![],
+            loader.declarations,
+            loader.definitions,
+            main,
+        ))
+    }
+
+    /// Load the program in `source`, which was read from `path`, along with the definitions of
+    /// every file it imports. `path` is only used to resolve imports and report errors, so it
+    /// need not exist
+    pub fn load_source(path: &Path, source: &str) -> Result<Self, SourceError> {
+        let mut loader = Loader::default();
+        loader
+            .visiting
+            .push((Loader::canonicalize(path), path.to_path_buf()));
+        let main = loader.load_source(path, source, false)?;
+        Ok(Program::new(
+            vec![],
+            loader.declarations,
+            loader.definitions,
+            main,
+        ))
+    }
+
+    /// Return the term this program runs, if it has one
+    pub fn main(&self) -> Option<&FcmcTerm> {
+        self.main.as_ref()
+    }
+
+    /// Return the names of this program's definitions, in the order they are defined
+    pub fn names(&self) -> impl Iterator<Item = &Var> {
+        self.definitions.iter().map(|(name, _)| name)
+    }
+
+    /// Add the imports, declarations and definitions of `other` to this program, replacing any
+    /// definitions with the same name, and take the main term of `other` if it has one
+    pub fn extend(&mut self, other: Program) {
+        self.imports.extend(other.imports);
+        self.declarations.extend(other.declarations);
+        for (name, body) in other.definitions {
+            self.definitions.retain(|(n, _)| *n != name);
+            self.definitions.push((name, body));
+        }
+        if other.main.is_some() {
+            self.main = other.main;
+        }
+    }
+
+    /// Returns true if `name` refers to a definition rather than a variable
+    pub fn is_reference(name: &str) -> bool {
+        name.starts_with(|c: char| c.is_ascii_uppercase())
+    }
+
+    /// Expand every reference to a definition in the main term, returning the closed
+    /// [`FcmcTerm`] to run
+    pub fn expand(&self) -> Result<FcmcTerm, DefinitionError> {
+        let mut bodies = HashMap::new();
+        for (name, body) in self.definitions.iter() {
+            if bodies.insert(name.clone(), body).is_some() {
+                return Err(DefinitionError::Duplicate { name: name.clone() });
+            }
+        }
+        let mut expanded = HashMap::new();
+        for (name, _) in self.definitions.iter() {
+            resolve(name, &bodies, &mut expanded, &mut vec![])?;
+        }
+        let main = self.main.as_ref().ok_or(DefinitionError::NoMainTerm)?;
+        substitute_references(main, &expanded)
+    }
+
+    /// Build the table of location kinds for `term`, the expanded main term of this program.
+    /// Without declarations, locations whose names start with a ~ are channels and all others are
+    /// stacks; once any location is declared, every location `term` uses must be declared
+    pub fn locations(&self, term: &FcmcTerm) -> Result<Locations, LocationError> {
+        if self.declarations.is_empty() {
+            return Ok(Locations::infer(term));
+        }
+        let mut locations = Locations::new();
+        for (name, kind) in self.declarations.iter() {
+            locations.declare(name.clone(), *kind)?;
+        }
+        locations.check(term)?;
+        Ok(locations)
+    }
+}
+
+/// Reads source files and follows their imports, collecting every definition in order
+#[derive(Default)]
+struct Loader {
+    /// Files that have been completely loaded, so shared imports are only read once
+    loaded: HashSet<PathBuf>,
+    /// The chain of files currently being loaded, as (canonical path, path as written)
+    visiting: Vec<(PathBuf, PathBuf)>,
+    declarations: Vec<(Var, LocationKind)>,
+    definitions: Vec<(Var, FcmcTerm)>,
+}
+
+impl Loader {
+    /// The canonical form of `path`, which need not exist as long as its directory does, so
+    /// that a file whose source was read elsewhere is still recognised when it is imported
+    fn canonicalize(path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            match (fs::canonicalize(dir), path.file_name()) {
+                (Ok(dir), Some(name)) => dir.join(name),
+                _ => path.to_path_buf(),
+            }
+        })
+    }
+
+    /// Load the file at `path` and its imports, returning its main term
+    fn load(&mut self, path: &Path, imported: bool) -> Result<Option<FcmcTerm>, SourceError> {
+        let error = |kind| SourceError {
+            path: path.to_path_buf(),
+            kind,
+        };
+        let canonical = fs::canonicalize(path).map_err(|e| error(SourceErrorKind::Io(e)))?;
+        if let Some(i) = self.visiting.iter().position(|(c, _)| *c == canonical) {
+            let mut cycle: Vec<PathBuf> =
+                self.visiting[i..].iter().map(|(_, p)| p.clone()).collect();
+            cycle.push(path.to_path_buf());
+            return Err(error(SourceErrorKind::ImportCycle(cycle)));
+        }
+        if self.loaded.contains(&canonical) {
+            return Ok(None);
+        }
+        let source = fs::read_to_string(path).map_err(|e| error(SourceErrorKind::Io(e)))?;
+        self.visiting.push((canonical.clone(), path.to_path_buf()));
+        let main = self.load_source(path, &source, imported)?;
+        self.visiting.pop();
+        self.loaded.insert(canonical);
+        Ok(main)
+    }
+
+    /// Parse `source`, which was read from `path`, and load its imports, returning its main term
+    fn load_source(
+        &mut self,
+        path: &Path,
+        source: &str,
+        imported: bool,
+    ) -> Result<Option<FcmcTerm>, SourceError> {
+        let error = |kind| SourceError {
+            path: path.to_path_buf(),
+            kind,
+        };
+        let program: Program = source
+            .parse()
+            .map_err(|e| error(SourceErrorKind::Parse(Box::new(e))))?;
+        if imported && program.main.is_some() {
+            return Err(error(SourceErrorKind::MainInImport));
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for import in program.imports.iter() {
+            self.load(&dir.join(import), true)?;
+        }
+        self.declarations.extend(program.declarations);
+        self.definitions.extend(program.definitions);
+        Ok(program.main)
+    }
+}
+
+/// Return the references to definitions that occur free in `term`, in a stable order
+fn references(term: &FcmcTerm) -> Vec<Var> {
+    let mut names: Vec<Var> = term
+        .free_variables()
+        .into_iter()
+        .filter(|name| Program::is_reference(name))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Replace every reference in `term` with its fully expanded definition
+fn substitute_references(
+    term: &FcmcTerm,
+    expanded: &HashMap<Var, FcmcTerm>,
+) -> Result<FcmcTerm, DefinitionError> {
+    let mut term = term.clone();
+    for name in references(&term) {
+        let body = expanded
+            .get(&name)
+            .ok_or(DefinitionError::Undefined { name: name.clone() })?;
+        term = term.substitute(&name, body);
+    }
+    Ok(term)
+}
+
+/// Expand the definition of `name` into `expanded`, after first expanding everything it refers to.
+/// `visiting` holds the chain of definitions currently being expanded, to detect recursion
+fn resolve(
+    name: &Var,
+    bodies: &HashMap<Var, &FcmcTerm>,
+    expanded: &mut HashMap<Var, FcmcTerm>,
+    visiting: &mut Vec<Var>,
+) -> Result<(), DefinitionError> {
+    if expanded.contains_key(name) {
+        return Ok(());
+    }
+    if let Some(i) = visiting.iter().position(|n| n == name) {
+        let mut cycle = visiting[i..].to_vec();
+        cycle.push(name.clone());
+        return Err(DefinitionError::Recursive { cycle });
+    }
+    let body = bodies
+        .get(name)
+        .ok_or(DefinitionError::Undefined { name: name.clone() })?;
+    visiting.push(name.clone());
+    for reference in references(body) {
+        resolve(&reference, bodies, expanded, visiting)?;
+    }
+    visiting.pop();
+    let body = substitute_references(body, expanded)?;
+    expanded.insert(name.clone(), body);
+    Ok(())
+}
+
+/// Returns true if `term` has a sequence that is not inside brackets, braces or parentheses, so it
+/// must be parenthesised to be the body of a definition
+fn is_sequenced(term: &FcmcTerm) -> bool {
+    match term {
+        FcmcTerm::Seq { .. } => true,
+        FcmcTerm::Pop { next, .. } | FcmcTerm::Push { next, .. } => is_sequenced(next),
+        FcmcTerm::Fork { cont, .. } => is_sequenced(cont),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Program {
+    /// Print the program as source that parses back to it, one import, declaration or
+    /// definition per line
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut lines = vec![];
+        for import in self.imports.iter() {
+            lines.push(format!("import \"{}\"", import));
+        }
+        for kind in [LocationKind::Channel, LocationKind::Stack] {
+            let names: Vec<&str> = self
+                .declarations
+                .iter()
+                .filter(|(_, k)| *k == kind)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if !names.is_empty() {
+                lines.push(format!("{} {};", kind, names.join(", ")));
+            }
+        }
+        for (name, body) in self.definitions.iter() {
+            if is_sequenced(body) {
+                lines.push(format!("def {} = ({});", name, body));
+            } else {
+                lines.push(format!("def {} = {};", name, body));
+            }
+        }
+        if let Some(main) = &self.main {
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            lines.push(main.to_string());
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProgramParser::new()
+            .parse(s)
+            .map_err(|e| ParseError::new(s, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{DefinitionError, LocationError, SourceErrorKind};
+    use crate::fcmc::{FcmcProgramState, FcmcTerm, LocationKind};
+    use crate::program::Program;
+    use crate::sink::Sink;
+    use std::path::Path;
+    use std::{env, fs, process};
+
+    #[test]
+    fn expands_definitions() {
+        let program: Program = "def Send = [[x]~out]~a;\n\
+                                def Recv = ~a<y>.y;\n\
+                                {Send}.Recv"
+            .parse()
+            .unwrap();
+        assert_eq!(program.expand(), Ok(FcmcTerm::term1()));
+    }
+
+    #[test]
+    fn expands_nested_definitions() {
+        let program: Program = "def Out = [x]~out;\n\
+                                def Send = [Out]~a;\n\
+                                {Send}.~a<y>.y"
+            .parse()
+            .unwrap();
+        let ans = FcmcProgramState::run(program.expand().unwrap());
+        assert_eq!(ans, FcmcProgramState::run(FcmcTerm::term1()));
+    }
+
+    #[test]
+    fn avoids_capture() {
+        let program: Program = "def Out = [x]~out;\n~a<x>.Out".parse().unwrap();
+        let expanded = program.expand().unwrap();
+        assert_eq!(expanded.to_string(), "~a<a>.[x]~out");
+    }
+
+    #[test]
+    fn rejects_undefined() {
+        let program: Program = "def Send = [Msg]~a;\n{Send}.~a<y>.y".parse().unwrap();
+        assert_eq!(
+            program.expand(),
+            Err(DefinitionError::Undefined {
+                name: "Msg".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_recursive() {
+        let program: Program = "def Ping = [Pong]~a;\ndef Pong = [Ping]~b;\nPing"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.expand(),
+            Err(DefinitionError::Recursive {
+                cycle: vec!["Ping".to_string(), "Pong".to_string(), "Ping".to_string()]
+            })
+        );
+    }
+
+    #[test]
+    fn declares_locations() {
+        let program: Program = "chan a, out;\nstack t;\n{[[x]out]a}.a<y>.[*]t.y"
+            .parse()
+            .unwrap();
+        let term = program.expand().unwrap();
+        let locations = program.locations(&term).unwrap();
+        assert_eq!(locations.kind("a"), Some(LocationKind::Channel));
+        assert_eq!(locations.kind("t"), Some(LocationKind::Stack));
+        let ans = FcmcProgramState::run_with_locations(term, locations, &Sink::None).unwrap();
+        assert_eq!(
+            ans,
+            vec![
+                ("out".to_string(), FcmcTerm::new_variable("x")),
+                ("t".to_string(), FcmcTerm::Skip)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_undeclared_location() {
+        let program: Program = "chan a;\n{[[x]out]a}.a<y>.y".parse().unwrap();
+        let term = program.expand().unwrap();
+        assert_eq!(
+            program.locations(&term),
+            Err(LocationError::Undeclared {
+                name: "out".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_declarations() {
+        let program: Program = "chan a;\nstack a;\na<y>.y".parse().unwrap();
+        let term = program.expand().unwrap();
+        assert_eq!(
+            program.locations(&term),
+            Err(LocationError::Conflict {
+                name: "a".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn prints_source() {
+        let program: Program = "import \"prelude.fcmc\"\nchan a, out;\ndef Run = (a<x>.x;*);\nRun"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.to_string(),
+            "import \"prelude.fcmc\"\nchan a, out;\ndef Run = (a<x>.x;*);\n\nRun"
+        );
+        for source in [
+            include_str!("../programs/relay.fcmc"),
+            include_str!("../programs/declared.fcmc"),
+            include_str!("../programs/prelude.fcmc"),
+        ] {
+            let program: Program = source.parse().unwrap();
+            assert_eq!(program.to_string().parse(), Ok(program));
+        }
+    }
+
+    #[test]
+    fn loads_source_relative_to_path() {
+        let source = "import \"prelude.fcmc\"\n[x]~a.{Relay}.{Run}.Join";
+        let program = Program::load_source(Path::new("programs/<stdin>"), source).unwrap();
+        assert_eq!(
+            FcmcProgramState::run(program.expand().unwrap()),
+            FcmcProgramState::run(FcmcTerm::term3())
+        );
+    }
+
+    #[test]
+    fn extends_definitions() {
+        let mut program: Program = "def Send = [[y]~out]~a;".parse().unwrap();
+        program.extend("def Send = [[x]~out]~a;".parse().unwrap());
+        program.extend("def Recv = ~a<y>.y;\n{Send}.Recv".parse().unwrap());
+        assert_eq!(program.names().collect::<Vec<_>>(), vec!["Send", "Recv"]);
+        assert_eq!(program.expand(), Ok(FcmcTerm::term1()));
+    }
+
+    #[test]
+    fn rejects_missing_main() {
+        let program: Program = "def X = *;".parse().unwrap();
+        assert_eq!(program.expand(), Err(DefinitionError::NoMainTerm));
+    }
+
+    #[test]
+    fn loads_imports() {
+        let program = Program::load(Path::new("programs/imports.fcmc")).unwrap();
+        assert_eq!(
+            FcmcProgramState::run(program.expand().unwrap()),
+            FcmcProgramState::run(FcmcTerm::term3())
+        );
+    }
+
+    #[test]
+    fn rejects_import_cycle() {
+        let dir = env::temp_dir().join(format!("fcmc-import-cycle-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.fcmc"), "import \"b.fcmc\"\n*").unwrap();
+        fs::write(dir.join("b.fcmc"), "import \"a.fcmc\"").unwrap();
+        let err = Program::load(&dir.join("a.fcmc")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        match err.kind {
+            SourceErrorKind::ImportCycle(cycle) => assert_eq!(
+                cycle,
+                vec![dir.join("a.fcmc"), dir.join("b.fcmc"), dir.join("a.fcmc")]
+            ),
+            kind => panic!("expected an import cycle, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn rejects_import_cycle_back_to_source() {
+        let dir = env::temp_dir().join(format!("fcmc-source-cycle-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.fcmc"), "import \"a.fcmc\"").unwrap();
+        let source = "import \"b.fcmc\"\n*";
+        fs::write(dir.join("a.fcmc"), source).unwrap();
+        let err = Program::load_source(&dir.join("a.fcmc"), source).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        match err.kind {
+            SourceErrorKind::ImportCycle(cycle) => assert_eq!(
+                cycle,
+                vec![dir.join("a.fcmc"), dir.join("b.fcmc"), dir.join("a.fcmc")]
+            ),
+            kind => panic!("expected an import cycle, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn reports_file_of_parse_error() {
+        let dir = env::temp_dir().join(format!("fcmc-import-error-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.fcmc"), "import \"lib.fcmc\"\nX").unwrap();
+        fs::write(dir.join("lib.fcmc"), "def X = [x]~out.;").unwrap();
+        let err = Program::load(&dir.join("main.fcmc")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(err.path, dir.join("lib.fcmc"));
+        assert!(matches!(err.kind, SourceErrorKind::Parse(_)));
+    }
+
+    #[test]
+    fn rejects_duplicate() {
+        let program: Program = "def X = *;\ndef X = *;\nX".parse().unwrap();
+        assert_eq!(
+            program.expand(),
+            Err(DefinitionError::Duplicate {
+                name: "X".to_string()
+            })
+        );
+    }
+}
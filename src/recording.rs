@@ -0,0 +1,218 @@
+use crate::error::RecordingError;
+use crate::lambdaterm::Var;
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+/// Whether a step pushes to a channel or pops from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Push,
+    Pop,
+}
+
+/// A step of a thread that pushes to or pops from a channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The number of the thread, which is 0 for the main thread
+    pub thread: usize,
+    pub access: Access,
+    pub channel: Var,
+}
+
+/// A thread forking another
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fork {
+    /// The number of the thread that forked
+    pub parent: usize,
+    /// The number the forked thread was given
+    pub child: usize,
+}
+
+/// The order in which the threads of a run took their steps on channels. Every other step only
+/// changes the thread taking it, so replaying the channel steps in this order gives the same run
+/// however the OS schedules the threads. Threads are numbered in the order they are forked, which
+/// can differ between runs when more than one thread forks, so the number each forked thread was
+/// given is kept too, and a thread given the same number again in the replay.
+///
+/// In text, each fork is a line with the number of the thread that forked, `fork` and the number
+/// of the forked thread. Each step follows on a line with the thread number, `push` or `pop`, and
+/// the channel:
+/// ```text
+/// 0 fork 1
+/// 1 push ~a
+/// 0 pop ~a
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub events: Vec<Event>,
+    /// In the order each thread forked, though not across threads
+    pub forks: Vec<Fork>,
+}
+
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for fork in &self.forks {
+            writeln!(f, "{} fork {}", fork.parent, fork.child)?;
+        }
+        for event in &self.events {
+            let access = match event.access {
+                Access::Push => "push",
+                Access::Pop => "pop",
+            };
+            writeln!(f, "{} {} {}", event.thread, access, event.channel)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = RecordingError;
+
+    /// Read a recording back from its text, skipping blank lines
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = vec![];
+        let mut forks = vec![];
+        for (i, text) in s.lines().enumerate() {
+            let error = |message: &str| RecordingError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let (thread, access, channel) =
+                match words[..] {
+                    [] => continue,
+                    [thread, access, channel] => (thread, access, channel),
+                    _ => return Err(error(
+                        "expected a thread number, then `push` or `pop` and a channel, or `fork` \
+                         and a thread number",
+                    )),
+                };
+            let thread = thread
+                .parse()
+                .map_err(|_| error("the thread must be a number"))?;
+            let access = match access {
+                "push" => Access::Push,
+                "pop" => Access::Pop,
+                "fork" => {
+                    let child = channel
+                        .parse()
+                        .map_err(|_| error("the forked thread must be a number"))?;
+                    forks.push(Fork {
+                        parent: thread,
+                        child,
+                    });
+                    continue;
+                }
+                _ => return Err(error("expected `push`, `pop` or `fork`")),
+            };
+            events.push(Event {
+                thread,
+                access,
+                channel: channel.to_string(),
+            });
+        }
+        Ok(Recording { events, forks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::RecordingError;
+    use crate::fcmc::{FcmcProgramState, FcmcTerm, Locations, RunOptions};
+    use crate::recording::{Access, Event, Fork, Recording};
+
+    #[test]
+    fn reads_back_text() {
+        let recording = Recording {
+            events: vec![
+                Event {
+                    thread: 1,
+                    access: Access::Push,
+                    channel: "~a".to_string(),
+                },
+                Event {
+                    thread: 0,
+                    access: Access::Pop,
+                    channel: "~a".to_string(),
+                },
+            ],
+            forks: vec![Fork {
+                parent: 0,
+                child: 1,
+            }],
+        };
+        assert_eq!(recording.to_string(), "0 fork 1\n1 push ~a\n0 pop ~a\n");
+        assert_eq!(recording.to_string().parse(), Ok(recording));
+    }
+
+    #[test]
+    fn reports_bad_line() {
+        let error = "1 push ~a\n\nx pop ~a".parse::<Recording>();
+        let expected = RecordingError {
+            line: 3,
+            message: "the thread must be a number".to_string(),
+        };
+        assert_eq!(error, Err(expected));
+    }
+
+    #[test]
+    fn replays_recording() {
+        let term: FcmcTerm = "{[a]~out}.{[b]~out}.~out<x>.[x]~out.[c]~out"
+            .parse()
+            .unwrap();
+        let locations = Locations::infer(&term);
+        let (recorded, recording) =
+            FcmcProgramState::record(term.clone(), locations.clone(), &RunOptions::default());
+        let options = RunOptions {
+            replay: Some(recording),
+            ..RunOptions::default()
+        };
+        for _ in 0..20 {
+            let replayed = FcmcProgramState::evaluate(term.clone(), locations.clone(), &options);
+            assert_eq!(replayed, recorded);
+        }
+    }
+
+    #[test]
+    fn replays_threads_forked_by_threads() {
+        // the threads forked by the two forked threads may be numbered either way round
+        let term: FcmcTerm = "{{[a]~out}.[b]~out}.{{[c]~out}.[d]~out}.*".parse().unwrap();
+        let locations = Locations::infer(&term);
+        for _ in 0..10 {
+            let (recorded, recording) =
+                FcmcProgramState::record(term.clone(), locations.clone(), &RunOptions::default());
+            assert_eq!(recording.forks.len(), 4);
+            let options = RunOptions {
+                replay: Some(recording),
+                ..RunOptions::default()
+            };
+            for _ in 0..10 {
+                let replayed =
+                    FcmcProgramState::evaluate(term.clone(), locations.clone(), &options);
+                assert_eq!(replayed, recorded);
+            }
+        }
+    }
+
+    #[test]
+    fn replays_given_order() {
+        let term: FcmcTerm = "{[a]~out}.[b]~out".parse().unwrap();
+        let locations = Locations::infer(&term);
+        for (recording, first, second) in [
+            ("1 push ~out\n0 push ~out", "a", "b"),
+            ("0 push ~out\n1 push ~out", "b", "a"),
+        ] {
+            let options = RunOptions {
+                replay: Some(recording.parse().unwrap()),
+                ..RunOptions::default()
+            };
+            let ans = FcmcProgramState::evaluate(term.clone(), locations.clone(), &options);
+            let expected = vec![
+                ("~out".to_string(), FcmcTerm::new_variable(first)),
+                ("~out".to_string(), FcmcTerm::new_variable(second)),
+            ];
+            assert_eq!(ans, Ok(expected));
+        }
+    }
+}
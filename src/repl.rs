@@ -0,0 +1,368 @@
+use crate::cli::{
+    evaluate, prepare, run_program, start_stepping, Failure, Input, Machine, Stepping,
+};
+use clap::ValueEnum;
+use fcmc::{
+    FcmcTerm, FmcTerm, LambdaTerm, LocationKind, Program, RunOptions, SLambdaTerm, Sink, Var,
+};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The commands understood by the REPL, shown by `:help`
+const HELP: &str = "\
+Enter a term to run it on the current machine. Commands:
+  :load <file>         run a file, keeping its declarations and definitions for fcmc
+  :def Name = <term>   define Name for later fcmc terms
+  :step <term>         start stepping through a term one transition at a time
+  :step                perform the next transition
+  :trace on|off        print every state while running terms
+  :machine <name>      switch to pam, kam, sam, fmc or fcmc
+  :type <term>         show how a term is parsed, and the kinds of its locations
+  :examples            list example terms for the current machine
+  :help                show this message
+  :quit                leave the REPL";
+
+/// An interactive session, remembering the machine in use and the definitions made so far
+pub struct Repl {
+    machine: Machine,
+    /// Whether to print every state, and what happens to threads left when an FCMC term finishes
+    options: RunOptions,
+    /// Declarations and definitions from `:load` and `:def`, used by every FCMC term
+    program: Program,
+    /// The machine being stepped through with `:step`
+    stepping: Option<Box<dyn Stepping>>,
+}
+
+impl Repl {
+    /// Create a new [`Repl`] that runs terms on `machine` with `options`
+    pub fn new(machine: Machine, options: RunOptions) -> Self {
+        Repl {
+            machine,
+            options,
+            program: Program::new(vec![], vec![], vec![], None),
+            stepping: None,
+        }
+    }
+
+    /// Read and evaluate lines until the user quits, with line editing and a history that is
+    /// kept between sessions in `~/.fcmc_history`
+    pub fn run(&mut self) -> rustyline::Result<()> {
+        let mut editor = DefaultEditor::new()?;
+        let history = history_path();
+        if let Some(path) = &history {
+            // there is no history the first time the REPL is used
+            let _ = editor.load_history(path);
+        }
+        println!("FCMC abstract machine. Type :help for a list of commands.");
+        loop {
+            let line = match editor.readline(&format!("{}> ", self.machine)) {
+                Ok(line) => line,
+                // ctrl-c abandons the current line
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line)?;
+            if line == ":quit" || line == ":q" {
+                break;
+            }
+            match self.eval(line) {
+                Ok(lines) => lines.iter().for_each(|l| println!("{}", l)),
+                Err(failure) => eprintln!("{}", failure),
+            }
+        }
+        if let Some(path) = &history {
+            editor.save_history(path)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluate a line of input, returning the lines to print
+    pub(crate) fn eval(&mut self, line: &str) -> Result<Vec<String>, Failure> {
+        let Some(command) = line.strip_prefix(':') else {
+            return self.run_source(&Input::new(Path::new("<repl>"), line));
+        };
+        let (command, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(c, a)| (c, a.trim()));
+        match (command, arg) {
+            ("help", _) => Ok(HELP.lines().map(str::to_string).collect()),
+            ("load", path) if !path.is_empty() => self.run_source(&Input::read(path)?),
+            ("def", definition) if !definition.is_empty() => self.define(definition),
+            ("step", term) => self.step(term),
+            ("trace", "") => {
+                let trace = !self.options.trace.is_none();
+                Ok(vec![format!("trace: {}", on_off(trace))])
+            }
+            ("trace", "on") => self.set_trace(true),
+            ("trace", "off") => self.set_trace(false),
+            ("machine", "") => Ok(vec![format!("machine: {}", self.machine)]),
+            ("machine", name) => {
+                self.machine = Machine::from_str(name, true).map_err(|_| {
+                    Failure::Input(format!(
+                        "error: unknown machine `{}`, expected pam, kam, sam, fmc or fcmc",
+                        name
+                    ))
+                })?;
+                self.stepping = None;
+                Ok(vec![format!("machine: {}", self.machine)])
+            }
+            ("type", term) if !term.is_empty() => self.describe(term),
+            ("examples", _) => Ok(self.examples()),
+            _ => Err(Failure::Input(format!(
+                "error: unknown command `:{}`, type :help for a list of commands",
+                line[1..].trim()
+            ))),
+        }
+    }
+
+    fn set_trace(&mut self, trace: bool) -> Result<Vec<String>, Failure> {
+        self.options.trace = if trace { Sink::Stdout } else { Sink::None };
+        Ok(vec![format!("trace: {}", on_off(trace))])
+    }
+
+    /// Run the term in `input` on the current machine. FCMC source may also import files and
+    /// declare and define names, which are kept for the rest of the session
+    fn run_source(&mut self, input: &Input) -> Result<Vec<String>, Failure> {
+        if self.machine != Machine::Fcmc {
+            return evaluate(self.machine, input, &self.options);
+        }
+        let loaded = input.load()?;
+        let runs = loaded.main().is_some();
+        let names: Vec<Var> = loaded.names().cloned().collect();
+        let mut program = self.program.clone();
+        program.extend(loaded);
+        let output = if runs {
+            run_program(input, &program, &self.options)?
+        } else {
+            names.iter().map(|n| format!("defined {}", n)).collect()
+        };
+        self.program = program;
+        Ok(output)
+    }
+
+    /// Add the definition `Name = term` to the session
+    fn define(&mut self, definition: &str) -> Result<Vec<String>, Failure> {
+        if self.machine != Machine::Fcmc {
+            return Err(Failure::Input(
+                "error: definitions can only be used on the fcmc machine".to_string(),
+            ));
+        }
+        let input = Input::new(Path::new("<repl>"), &format!("def {};", definition));
+        if input.load()?.main().is_some() {
+            return Err(Failure::Input(
+                "error: a definition cannot contain a top-level `;`, put its body in parentheses"
+                    .to_string(),
+            ));
+        }
+        self.run_source(&input)
+    }
+
+    /// Start stepping through `term`, or perform the next step if `term` is empty
+    fn step(&mut self, term: &str) -> Result<Vec<String>, Failure> {
+        if !term.is_empty() {
+            let input = Input::new(Path::new("<repl>"), term);
+            let stepper = start_stepping(self.machine, &input, &self.program, &self.options)?;
+            let lines = state_lines(&*stepper);
+            self.stepping = Some(stepper);
+            return Ok(lines);
+        }
+        let Some(stepper) = &mut self.stepping else {
+            return Err(Failure::Input(
+                "error: nothing to step, start with :step <term>".to_string(),
+            ));
+        };
+        if let Err(failure) = stepper.step() {
+            self.stepping = None;
+            return Err(failure);
+        }
+        let mut lines = state_lines(&**stepper);
+        if stepper.is_final() {
+            lines.extend(stepper.output());
+            self.stepping = None;
+        }
+        Ok(lines)
+    }
+
+    /// Show how `term` is parsed on the current machine. For FCMC, this is after its definitions
+    /// are expanded, followed by the kind of every location it uses
+    fn describe(&self, term: &str) -> Result<Vec<String>, Failure> {
+        let input = Input::new(Path::new("<repl>"), term);
+        Ok(match self.machine {
+            Machine::Pam | Machine::Kam => vec![input.parse::<LambdaTerm>()?.to_string()],
+            Machine::Sam => vec![input.parse::<SLambdaTerm>()?.to_string()],
+            Machine::Fmc => vec![input.parse::<FmcTerm>()?.to_string()],
+            Machine::Fcmc => {
+                let mut program = self.program.clone();
+                program.extend(input.load()?);
+                let (term, locations) = prepare(&input, &program)?;
+                let mut lines = vec![term.to_string()];
+                let used = term.locations();
+                let mut kinds: Vec<(&Var, LocationKind)> =
+                    [LocationKind::Channel, LocationKind::Stack]
+                        .into_iter()
+                        .flat_map(|kind| locations.of_kind(kind).map(move |name| (name, kind)))
+                        .filter(|(name, _)| used.contains(*name))
+                        .collect();
+                kinds.sort_by_key(|(name, _)| *name);
+                lines.extend(
+                    kinds
+                        .iter()
+                        .map(|(name, kind)| format!("{}: {}", name, kind)),
+                );
+                lines
+            }
+        })
+    }
+
+    /// List the example terms for the current machine
+    fn examples(&self) -> Vec<String> {
+        let terms: Vec<String> = match self.machine {
+            Machine::Pam | Machine::Kam => vec![
+                LambdaTerm::term1().to_string(),
+                LambdaTerm::term2().to_string(),
+                LambdaTerm::example5().to_string(),
+                LambdaTerm::example6().to_string(),
+                LambdaTerm::example7().to_string(),
+            ],
+            Machine::Sam => vec![
+                SLambdaTerm::term1().to_string(),
+                SLambdaTerm::term2().to_string(),
+                SLambdaTerm::term3().to_string(),
+                SLambdaTerm::term4().to_string(),
+            ],
+            Machine::Fmc => vec![FmcTerm::term1().to_string(), FmcTerm::term2().to_string()],
+            Machine::Fcmc => vec![
+                FcmcTerm::term1().to_string(),
+                FcmcTerm::term2().to_string(),
+                FcmcTerm::term3().to_string(),
+            ],
+        };
+        terms
+            .iter()
+            .enumerate()
+            .map(|(i, t)| format!("{}. {}", i + 1, t))
+            .collect()
+    }
+}
+
+/// The state of a machine being stepped through, which takes a line for each FCMC thread
+fn state_lines(stepper: &dyn Stepping) -> Vec<String> {
+    stepper.to_string().lines().map(str::to_string).collect()
+}
+
+fn on_off(flag: bool) -> &'static str {
+    if flag {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// The file the REPL keeps its history in, if there is a home directory to put it in
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".fcmc_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Machine;
+    use crate::repl::Repl;
+    use fcmc::RunOptions;
+
+    /// Evaluate each line in turn, returning the output of the last
+    fn eval(repl: &mut Repl, lines: &[&str]) -> Vec<String> {
+        let mut output = vec![];
+        for line in lines {
+            output = repl
+                .eval(line)
+                .unwrap_or_else(|e| panic!("{}: {}", line, e));
+        }
+        output
+    }
+
+    #[test]
+    fn runs_with_definitions() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        let output = eval(
+            &mut repl,
+            &[
+                ":def Send = [[x]~out]~a",
+                ":def Recv = ~a<y>.y",
+                "{Send}.Recv",
+            ],
+        );
+        assert_eq!(output, vec!["~out: x"]);
+    }
+
+    #[test]
+    fn loads_definitions() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        let output = eval(&mut repl, &[":load programs/prelude.fcmc"]);
+        assert_eq!(output, vec!["defined Relay", "defined Run", "defined Join"]);
+        let output = eval(&mut repl, &["[x]~a.{Relay}.{Run}.Join"]);
+        assert_eq!(output, vec!["~out: x"]);
+    }
+
+    #[test]
+    fn rejects_sequenced_definition() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        assert!(repl.eval(":def Run = a<x>.x;*").is_err());
+        assert_eq!(
+            eval(&mut repl, &[":def Run = (a<x>.x;*)"]),
+            vec!["defined Run"]
+        );
+    }
+
+    #[test]
+    fn steps_through_term() {
+        let mut repl = Repl::new(Machine::Kam, RunOptions::default());
+        eval(&mut repl, &[r":step (\x. x) y"]);
+        assert_eq!(eval(&mut repl, &[":step"]), vec!["(\\x. x, [], (y, []))"]);
+        let output = eval(&mut repl, &[":step", ":step"]);
+        assert_eq!(output, vec!["(y, [], [])", "y"]);
+        assert!(repl.eval(":step").is_err());
+    }
+
+    #[test]
+    fn steps_through_threads() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        eval(&mut repl, &[":step {[x]~a}.~a<y>.[y]b"]);
+        // the forked thread is shown until it finishes
+        let output = eval(&mut repl, &[":step"]);
+        assert_eq!(output, vec!["0: ~a<y>.[y]b, []", "1: [x]~a, []"]);
+        let output = eval(&mut repl, &[":step", ":step", ":step"]);
+        assert_eq!(output, vec![r#"0: *, [("y", x, [])]"#, "b: x"]);
+        assert!(repl.eval(":step").is_err());
+    }
+
+    #[test]
+    fn describes_locations() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        let output = eval(&mut repl, &[":type {[[x]~out]~a}.~a<y>.[y]b"]);
+        assert_eq!(
+            output,
+            vec![
+                "{[[x]~out]~a}.~a<y>.[y]b",
+                "b: stack",
+                "~a: chan",
+                "~out: chan"
+            ]
+        );
+    }
+
+    #[test]
+    fn switches_machine() {
+        let mut repl = Repl::new(Machine::Fcmc, RunOptions::default());
+        assert_eq!(eval(&mut repl, &[":machine SAM"]), vec!["machine: sam"]);
+        assert_eq!(eval(&mut repl, &["[x].[y]"]), vec!["y", "x"]);
+        assert!(repl.eval(":machine tm").is_err());
+        assert!(repl.eval(":def X = *").is_err());
+    }
+}
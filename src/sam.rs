@@ -1,6 +1,11 @@
+use crate::error::{MachineError, ParseError};
 use crate::lambdaterm::Var;
+use crate::machine::{AbstractMachine, Driver};
+use crate::parser::SLambdaTermParser;
+use crate::sink::Sink;
 use std::fmt;
 use std::fmt::Formatter;
+use std::str::FromStr;
 
 /// The sequential lambda-calculus takes an altered version of the lambda-calculus as grammar
 #[derive(Clone, Debug, PartialEq)]
@@ -54,6 +59,18 @@ impl SLambdaTerm {
             next: box next,
         }
     }
+
+    /// Returns true if self prints as a sequence or ends in a prefix that scopes over everything
+    /// to its right, so it needs parentheses on the left of a `;`
+    fn is_open(&self) -> bool {
+        match self {
+            SLambdaTerm::Pop { next, .. } | SLambdaTerm::Push { next, .. } => {
+                **next != SLambdaTerm::Skip
+            }
+            SLambdaTerm::Seq { .. } => true,
+            SLambdaTerm::Skip | SLambdaTerm::Variable { .. } => false,
+        }
+    }
 }
 
 impl fmt::Display for SLambdaTerm {
@@ -69,14 +86,28 @@ impl fmt::Display for SLambdaTerm {
                 SLambdaTerm::Skip => write!(f, "[{}]", term),
                 _ => write!(f, "[{}].{}", term, next),
             },
-            SLambdaTerm::Seq { term, next } => match **next {
-                SLambdaTerm::Skip => write!(f, "{}", term),
-                _ => write!(f, "{};{}", term, next),
-            },
+            // the left of a `;` is parenthesised only when it would otherwise extend over the `;`
+            SLambdaTerm::Seq { term, next } => {
+                if term.is_open() {
+                    write!(f, "({});{}", term, next)
+                } else {
+                    write!(f, "{};{}", term, next)
+                }
+            }
         }
     }
 }
 
+impl FromStr for SLambdaTerm {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SLambdaTermParser::new()
+            .parse(s)
+            .map_err(|e| ParseError::new(s, e))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct SClosure {
     term: SLambdaTerm,
@@ -98,7 +129,7 @@ impl SClosure {
                     SLambdaTerm::Variable { name }
                 } else {
                     let env_last = self.env.pop().unwrap();
-                    if &env_last.0 == &name {
+                    if env_last.0 == name {
                         env_last.1.retrieve_term()
                     } else {
                         self.term = SLambdaTerm::Variable { name };
@@ -162,14 +193,37 @@ impl SState {
         }
     }
 
-    /// Create a start state: ['SState'] from a term: ['SLambdaTerm'] and a stack: ['Vec<SClosure>']
-    fn start(t: SLambdaTerm, s: Vec<SClosure>) -> Self {
-        SState::new(SClosure::new(t, vec![]), s, vec![])
+    /// Run the given ['SLambdaTerm'] on the sequential abstract machine, writing each step and
+    /// then the output, top of the stack first, to `sink`, and stopping after `fuel` steps if it
+    /// is set
+    pub fn run_with(
+        term: SLambdaTerm,
+        fuel: Option<usize>,
+        sink: &Sink,
+    ) -> Result<Vec<SLambdaTerm>, MachineError<SClosure>> {
+        let ans = Driver::<SState>::new(fuel)
+            .on_state(|s| sink.line(format_args!("{}", s)))
+            .run(term)?;
+        for t in ans.iter().rev() {
+            sink.line(format_args!("{}", t));
+        }
+        Ok(ans)
+    }
+}
+
+impl AbstractMachine for SState {
+    type Term = SLambdaTerm;
+    type Closure = SClosure;
+    type Output = Vec<SLambdaTerm>;
+
+    /// Create a start state: ['SState'] with an empty stack from a term: ['SLambdaTerm']
+    fn start(t: SLambdaTerm) -> Self {
+        SState::new(SClosure::new(t, vec![]), vec![], vec![])
     }
 
     /// Perform a single step in-place of the abstract machine by mutating ['self'][SState]
     /// returns an error if the term is not a valid program ie. cannot be run
-    pub fn step(&mut self) -> Result<(), String> {
+    fn step(&mut self) -> Result<(), MachineError<SClosure>> {
         match self.closure.term.clone() {
             SLambdaTerm::Skip => {
                 if !self.final_() {
@@ -177,18 +231,29 @@ impl SState {
                 }
             }
             SLambdaTerm::Variable { name } => {
-                let env_last = self.closure.env.pop().unwrap();
-                if name.clone() == env_last.0 {
+                let env_last =
+                    self.closure
+                        .env
+                        .pop()
+                        .ok_or_else(|| MachineError::UnboundVariable {
+                            name: name.clone(),
+                            closure: self.closure.clone(),
+                        })?;
+                if name == env_last.0 {
                     self.closure = env_last.1;
                 }
             }
             SLambdaTerm::Pop { arg, next } => {
                 if self.stack.is_empty() {
-                    return Err("Term cannot be executed. Pop action encountered but execution stack is empty.".to_string());
+                    // the only stack of the SAM is the λ location of the FMC
+                    return Err(MachineError::EmptyLocation {
+                        location: "λ".to_string(),
+                        closure: self.closure.clone(),
+                    });
                 } else {
                     let stack_last = self.stack.pop().unwrap();
                     self.closure.term = *next;
-                    self.closure.env.push((arg.clone(), stack_last.clone()))
+                    self.closure.env.push((arg, stack_last))
                 }
             }
             SLambdaTerm::Push { term, next } => {
@@ -222,29 +287,15 @@ impl SState {
         }
     }
 
-    /// Run the given ['SLambdaTerm'] on the sequential abstract machine, printing each step and the output
-    pub fn run(term: SLambdaTerm) -> Vec<SLambdaTerm> {
-        let mut s = SState::start(term, vec![]);
-        while !s.final_() {
-            println!("{}", s);
-            match s.step() {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error during step: \"{}\"", e);
-                    return vec![];
-                }
-            }
+    /// The error for stopping in this state after `limit` steps
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<SClosure> {
+        MachineError::StepLimitExceeded {
+            limit,
+            closure: self.closure.clone(),
+            stack: self.stack.clone(),
+            continuation: self.continuation.clone(),
+            memory: vec![],
         }
-        println!("{}", s);
-        let ans = s.clone().readback();
-        let len = ans.len();
-        for (i, t) in ans.iter().rev().enumerate() {
-            print!("{}", t);
-            if i < len - 1 {
-                println!(", ");
-            }
-        }
-        ans
     }
 }
 
@@ -285,12 +336,14 @@ impl fmt::Display for SState {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::MachineError;
+    use crate::machine::AbstractMachine;
     use crate::sam::{SClosure, SLambdaTerm, SState};
 
     #[test]
     fn prints_seq_state() {
         let s = SState::state1();
-        assert_eq!(s.to_string(), "([<x>.[x]].<f>.f;f;f, [(\"y\", [<x>.[x]].<f>.f;f;f, [])], [([<x>.[x]].<f>.f;f;f, [(\"y\", [<x>.[x]].<f>.f;f;f, [])]), (<x>.<y>, [(\"y\", <x>.[x].[x], [])])], [])");
+        assert_eq!(s.to_string(), "([<x>.[x]].<f>.f;f;f;*, [(\"y\", [<x>.[x]].<f>.f;f;f;*, [])], [([<x>.[x]].<f>.f;f;f;*, [(\"y\", [<x>.[x]].<f>.f;f;f;*, [])]), (<x>.<y>, [(\"y\", <x>.[x].[x], [])])], [])");
     }
 
     #[test]
@@ -298,13 +351,13 @@ mod tests {
         let c = SClosure::closure1();
         assert_eq!(
             c.to_string(),
-            "[<x>.[x]].<f>.f;f;f, [(\"y\", [<x>.[x]].<f>.f;f;f, [])]"
+            "[<x>.[x]].<f>.f;f;f;*, [(\"y\", [<x>.[x]].<f>.f;f;f;*, [])]"
         );
     }
 
     #[test]
     fn term_into_state() {
-        let s = SState::start(SLambdaTerm::term1(), vec![]);
+        let s = SState::start(SLambdaTerm::term1());
         assert_eq!(s.to_string(), "(<x>.[x].[x], [], [], [])");
     }
 
@@ -324,7 +377,7 @@ mod tests {
 
     #[test]
     fn run_term4() {
-        let ans = SState::run(SLambdaTerm::term4());
+        let ans = SState::run(SLambdaTerm::term4()).unwrap();
         assert_eq!(
             ans,
             vec![
@@ -343,4 +396,14 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn reports_empty_stack() {
+        let term: SLambdaTerm = "<x>.x".parse().unwrap();
+        let expected = MachineError::EmptyLocation {
+            location: "λ".to_string(),
+            closure: SClosure::new(term.clone(), vec![]),
+        };
+        assert_eq!(SState::run(term), Err(expected));
+    }
 }
@@ -0,0 +1,567 @@
+use crate::error::MachineError;
+use crate::fcmc::{
+    self, CancelToken, ExitPolicy, FcmcClosure, FcmcTerm, FcmcThreadState, Locations, Memory,
+    Outcome, RunOptions, Snapshot, Transition,
+};
+use crate::lambdaterm::Var;
+use crate::machine::AbstractMachine;
+use crate::sink::Sink;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+
+/// Chooses which thread takes the next step when a [`Scheduler`] interleaves them
+pub trait Policy {
+    /// Choose one of the `ready` threads, given by number in increasing order, returning its
+    /// position in `ready`. There is always at least one thread ready
+    fn pick(&mut self, ready: &[usize]) -> usize;
+}
+
+/// Give each ready thread one step in turn, in order of thread number
+#[derive(Clone, Debug, Default)]
+pub struct RoundRobin {
+    /// The thread that took the last step
+    last: Option<usize>,
+}
+
+impl Policy for RoundRobin {
+    fn pick(&mut self, ready: &[usize]) -> usize {
+        // the first thread after the last one to step, going back to the start after the last
+        let next = ready
+            .iter()
+            .position(|&id| self.last.map_or(true, |last| id > last))
+            .unwrap_or(0);
+        self.last = Some(ready[next]);
+        next
+    }
+}
+
+/// Choose the ready thread to step at random, from a generator seeded so that the same seed
+/// always gives the same choices. The default generator has the seed 0
+#[derive(Clone, Debug, Default)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    /// The next number from a splitmix64 generator, which is good enough to pick threads with and
+    /// does not change between versions of a dependency
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Policy for Random {
+    fn pick(&mut self, ready: &[usize]) -> usize {
+        (self.next() % ready.len() as u64) as usize
+    }
+}
+
+/// The threads of an FCMC program run on a single OS thread, by thread number
+#[derive(Debug)]
+pub(crate) struct Pool {
+    /// The threads that have not finished. The main thread is kept once it has finished, as the
+    /// output is read back from its memory
+    threads: BTreeMap<usize, FcmcThreadState>,
+    /// The closure each forked thread started with
+    forked: HashMap<usize, FcmcClosure>,
+}
+
+impl Pool {
+    /// Create a [`Pool`] whose main thread runs a term with the locations in `locations`, and
+    /// whose threads may each take `fuel` steps if it is set
+    pub(crate) fn new(term: FcmcTerm, locations: Locations, fuel: Option<usize>) -> Self {
+        let mut main = FcmcThreadState::new(
+            FcmcClosure::new(term, vec![]),
+            vec![],
+            Memory::new(locations),
+        );
+        main.fuel = fuel;
+        Pool {
+            threads: BTreeMap::from([(main.id(), main)]),
+            forked: HashMap::new(),
+        }
+    }
+
+    /// Returns true once the main thread has finished, and the forked threads too unless `exit`
+    /// stops them
+    pub(crate) fn final_(&self, exit: ExitPolicy) -> bool {
+        self.threads[&0].final_()
+            && (exit == ExitPolicy::MainExitTerminates || self.threads.len() == 1)
+    }
+
+    /// The threads that can take a step, in increasing order
+    pub(crate) fn ready(&self) -> Vec<usize> {
+        self.threads
+            .values()
+            .filter(|thread| !thread.final_() && thread.waiting_on().is_none())
+            .map(FcmcThreadState::id)
+            .collect()
+    }
+
+    /// The error for stopping the thread `id` after `limit` steps
+    pub(crate) fn step_limit_exceeded(&self, id: usize, limit: usize) -> MachineError<FcmcClosure> {
+        self.threads[&id].step_limit_exceeded(limit)
+    }
+
+    /// The channel the next step of the thread `id` touches, if it touches one
+    pub(crate) fn channel(&self, id: usize) -> Option<&Var> {
+        self.threads.get(&id)?.channel()
+    }
+
+    /// Perform a single transition of the ready thread `id`, writing it to `trace`. The error of
+    /// a forked thread is returned as [`InThread`][MachineError::InThread], and the thread is
+    /// dropped
+    pub(crate) fn step(
+        &mut self,
+        id: usize,
+        trace: &Sink,
+    ) -> Result<(), MachineError<FcmcClosure>> {
+        let thread = self.threads.get_mut(&id).expect("ready threads are live");
+        trace.line(format_args!("{}: {}", id, thread.closure()));
+        match thread.transition() {
+            // waking the threads waiting on a channel is left to the next choice of ready threads
+            Ok(Transition::Stepped | Transition::Pushed(_) | Transition::Blocked(_)) => {}
+            Ok(Transition::Forked(new_thread)) => {
+                trace.line(format_args!(
+                    "New thread {} spawned: {}",
+                    new_thread.id(),
+                    new_thread.closure().term()
+                ));
+                self.forked
+                    .insert(new_thread.id(), new_thread.closure().clone());
+                self.threads.insert(new_thread.id(), new_thread);
+            }
+            Err(error) if id == 0 => return Err(error),
+            Err(error) => {
+                self.threads.remove(&id);
+                return Err(MachineError::InThread {
+                    thread: id,
+                    forked: self.forked[&id].clone(),
+                    error: box error,
+                });
+            }
+        }
+        if id != 0 && self.threads[&id].final_() {
+            self.threads.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// The deadlock of the threads left, when they are all waiting on empty channels
+    pub(crate) fn deadlock(&self) -> MachineError<FcmcClosure> {
+        let blocked = self
+            .threads
+            .values()
+            .filter_map(|thread| {
+                let location = thread.waiting_on()?;
+                Some((thread.id(), location.clone(), thread.closure().clone()))
+            })
+            .collect();
+        MachineError::Deadlock { blocked }
+    }
+
+    /// Empty the memory of the main thread, returning the closures that were in each location
+    pub(crate) fn drain(&mut self) -> Vec<(Var, FcmcClosure)> {
+        let main = self.threads.get_mut(&0).expect("the main thread is kept");
+        main.memory.drain()
+    }
+
+    /// Read back the final state of memory from the main thread, emptying its channels
+    pub(crate) fn readback(&mut self) -> Vec<(Var, FcmcTerm)> {
+        let main = self.threads.get_mut(&0).expect("the main thread is kept");
+        main.memory.readback()
+    }
+
+    /// A [`Snapshot`] of the threads, the same for the same state however it was reached
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        fcmc::snapshot(&self.threads)
+    }
+
+    /// Copy the pool, giving the copy channels of its own so that stepping it leaves this pool
+    /// as it was
+    pub(crate) fn duplicate(&self) -> Self {
+        Pool {
+            threads: fcmc::duplicate(&self.threads),
+            forked: self.forked.clone(),
+        }
+    }
+}
+
+/// Runs every thread of an FCMC program on the calling thread, interleaving their steps as
+/// chosen by a [`Policy`]. Unlike [`FcmcProgramState::evaluate`][crate::FcmcProgramState::evaluate]
+/// with OS threads, the same policy always gives the same run and the same trace
+#[derive(Debug)]
+pub struct Scheduler<P> {
+    pool: Pool,
+    policy: P,
+    trace: Sink,
+    exit: ExitPolicy,
+    /// How long the run may take, and when that time is up, if it is limited
+    timeout: Option<(Duration, Instant)>,
+    /// Stops the run when cancelled
+    cancel: Option<CancelToken>,
+    /// The error of the first forked thread to fail
+    failure: Option<MachineError<FcmcClosure>>,
+}
+
+impl<P: Policy> Scheduler<P> {
+    /// Create a [`Scheduler`] for a term whose locations have the kinds given in `locations`,
+    /// with tracing, the exit policy, the fuel of each thread, the timeout and the cancel token
+    /// set by `options`. The timeout counts from now. The schedule in `options` is ignored, as
+    /// `policy` takes its place, and so is the recording to replay
+    pub fn new(term: FcmcTerm, locations: Locations, policy: P, options: &RunOptions) -> Self {
+        Scheduler {
+            pool: Pool::new(term, locations, options.fuel),
+            policy,
+            trace: options.trace.clone(),
+            exit: options.exit,
+            timeout: options
+                .timeout
+                .map(|timeout| (timeout, Instant::now() + timeout)),
+            cancel: options.cancel.clone(),
+            failure: None,
+        }
+    }
+
+    /// The error for stopping the run, with what is left in memory, once it has been cancelled
+    /// or has run out of time
+    fn interruption(&mut self) -> Option<MachineError<FcmcClosure>> {
+        if self
+            .cancel
+            .as_ref()
+            .map_or(false, CancelToken::is_cancelled)
+        {
+            return Some(MachineError::Cancelled {
+                memory: self.pool.drain(),
+            });
+        }
+        match self.timeout {
+            Some((timeout, deadline)) if Instant::now() >= deadline => {
+                Some(MachineError::TimedOut {
+                    timeout,
+                    memory: self.pool.drain(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Perform a single transition of the thread chosen by the policy, returning its number, or
+    /// [`None`] if the program has finished. An error of the main thread is returned straight
+    /// away, while that of a forked thread is kept until the program finishes or deadlocks. Once
+    /// the run is cancelled or its timeout has passed, no more steps are taken and the error
+    /// carries what was left in memory
+    pub fn step_thread(&mut self) -> Result<Option<usize>, MachineError<FcmcClosure>> {
+        if self.pool.final_(self.exit) {
+            return Ok(None);
+        }
+        if let Some(interruption) = self.interruption() {
+            return Err(interruption);
+        }
+        let ready = self.pool.ready();
+        if ready.is_empty() {
+            // a thread that failed may be why the others are blocked, so it is reported first
+            return Err(self.failure.take().unwrap_or_else(|| self.pool.deadlock()));
+        }
+        let id = ready[self.policy.pick(&ready)];
+        match self.pool.step(id, &self.trace) {
+            Err(error) if id == 0 => return Err(error),
+            Err(error) => {
+                self.failure.get_or_insert(error);
+            }
+            Ok(()) => {}
+        }
+        Ok(Some(id))
+    }
+
+    /// Step the threads until the program finishes, returning the final state of memory, or the
+    /// error of the first forked thread to fail
+    pub fn finish(mut self) -> Outcome {
+        while self.step_thread()?.is_some() {}
+        if let Some(failure) = self.failure {
+            return Err(failure);
+        }
+        Ok(self.pool.readback())
+    }
+}
+
+/// The FCMC machine as a whole, with every thread run by the scheduler. The threads are started
+/// with the locations inferred from their names, and a forked thread that fails keeps the program
+/// from finishing, its error being returned by the next step
+impl<P: Policy + Default> AbstractMachine for Scheduler<P> {
+    type Term = FcmcTerm;
+    type Closure = FcmcClosure;
+    type Output = Vec<(Var, FcmcTerm)>;
+
+    fn start(term: FcmcTerm) -> Self {
+        let locations = Locations::infer(&term);
+        Scheduler::new(term, locations, P::default(), &RunOptions::default())
+    }
+
+    fn step(&mut self) -> Result<(), MachineError<FcmcClosure>> {
+        if self.step_thread()?.is_none() {
+            if let Some(failure) = self.failure.take() {
+                return Err(failure);
+            }
+        }
+        Ok(())
+    }
+
+    fn final_(&self) -> bool {
+        self.pool.final_(self.exit) && self.failure.is_none()
+    }
+
+    fn readback(&mut self) -> Vec<(Var, FcmcTerm)> {
+        self.pool.readback()
+    }
+
+    /// The error carries the state of the first thread that could take the next step
+    fn step_limit_exceeded(&self, limit: usize) -> MachineError<FcmcClosure> {
+        let id = self.pool.ready().first().copied().unwrap_or(0);
+        self.pool.step_limit_exceeded(id, limit)
+    }
+}
+
+/// Each thread that has not finished, and the main thread, on a line of its own by thread number
+impl<P> fmt::Display for Scheduler<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, (id, thread)) in self.pool.threads.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", id, thread.closure())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MachineError;
+    use crate::fcmc::{
+        CancelToken, ExitPolicy, FcmcClosure, FcmcTerm, Locations, Outcome, RunOptions, Schedule,
+    };
+    use crate::scheduler::{Policy, Random, RoundRobin, Scheduler};
+    use crate::sink::Sink;
+    use crate::FcmcProgramState;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Run `term` with `policy`, returning the thread that took each step and the output
+    fn schedule<P: Policy>(term: &str, policy: P, exit: ExitPolicy) -> (Vec<usize>, Outcome) {
+        let term: FcmcTerm = term.parse().unwrap();
+        let locations = Locations::infer(&term);
+        let options = RunOptions {
+            exit,
+            ..RunOptions::default()
+        };
+        let mut scheduler = Scheduler::new(term, locations, policy, &options);
+        let mut steps = vec![];
+        loop {
+            match scheduler.step_thread() {
+                Ok(Some(id)) => steps.push(id),
+                Ok(None) => return (steps, scheduler.finish()),
+                Err(error) => return (steps, Err(error)),
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let (steps, ans) = schedule(
+            "{[x]a.[y]a}.[z]b.*",
+            RoundRobin::default(),
+            ExitPolicy::WaitForAll,
+        );
+        assert_eq!(steps, vec![0, 1, 0, 1]);
+        assert_eq!(
+            ans,
+            Ok(vec![("b".to_string(), FcmcTerm::new_variable("z"))])
+        );
+    }
+
+    #[test]
+    fn traces_each_step() {
+        let term: FcmcTerm = "{[x]a.[y]a}.[z]b.*".parse().unwrap();
+        let trace = Sink::buffer();
+        let options = RunOptions {
+            trace: trace.clone(),
+            ..RunOptions::default()
+        };
+        let locations = Locations::infer(&term);
+        Scheduler::new(term, locations, RoundRobin::default(), &options)
+            .finish()
+            .unwrap();
+        let written = trace.contents().unwrap();
+        let starts: Vec<&str> = written
+            .lines()
+            .map(|line| line.split(':').next().unwrap())
+            .collect();
+        assert_eq!(starts, vec!["0", "New thread 1 spawned", "1", "0", "1"]);
+    }
+
+    #[test]
+    fn random_is_reproducible() {
+        let term = "{[a]~out.[b]~out}.{[c]~out}.[d]~out.*";
+        let outputs: Vec<_> = (0..8)
+            .map(|seed| schedule(term, Random::new(seed), ExitPolicy::WaitForAll))
+            .collect();
+        for (seed, output) in outputs.iter().enumerate() {
+            let again = schedule(term, Random::new(seed as u64), ExitPolicy::WaitForAll);
+            assert_eq!(output, &again);
+        }
+        // different seeds interleave the pushes differently
+        assert!(outputs.iter().any(|output| output.1 != outputs[0].1));
+    }
+
+    #[test]
+    fn waits_on_channels() {
+        let term = "{~a<x>.[x]~out}.[y]~a.*";
+        let (steps, ans) = schedule(term, RoundRobin::default(), ExitPolicy::WaitForAll);
+        // the forked thread cannot pop until the main thread has pushed
+        assert_eq!(steps, vec![0, 0, 1, 1]);
+        assert_eq!(
+            ans,
+            Ok(vec![("~out".to_string(), FcmcTerm::new_variable("y"))])
+        );
+    }
+
+    #[test]
+    fn detects_deadlock() {
+        let term = "{~a<x>.[x]~b}.~b<y>.[y]~a";
+        let (_, ans) = schedule(term, RoundRobin::default(), ExitPolicy::MainExitTerminates);
+        let expected = MachineError::Deadlock {
+            blocked: vec![
+                (
+                    0,
+                    "~b".to_string(),
+                    FcmcClosure::new("~b<y>.[y]~a".parse().unwrap(), vec![]),
+                ),
+                (
+                    1,
+                    "~a".to_string(),
+                    FcmcClosure::new("~a<x>.[x]~b".parse().unwrap(), vec![]),
+                ),
+            ],
+        };
+        assert_eq!(ans, Err(expected));
+    }
+
+    #[test]
+    fn terminates_forked_threads() {
+        let term = "{~a<x>.[x]~out}.*";
+        let (_, ans) = schedule(term, RoundRobin::default(), ExitPolicy::MainExitTerminates);
+        assert_eq!(ans, Ok(vec![]));
+    }
+
+    #[test]
+    fn reports_failed_thread() {
+        let (_, ans) = schedule("{z}.[x]a", RoundRobin::default(), ExitPolicy::WaitForAll);
+        let forked = FcmcClosure::new(FcmcTerm::new_variable("z"), vec![]);
+        let expected = MachineError::InThread {
+            thread: 1,
+            forked: forked.clone(),
+            error: box MachineError::UnboundVariable {
+                name: "z".to_string(),
+                closure: forked,
+            },
+        };
+        assert_eq!(ans, Err(expected));
+    }
+
+    #[test]
+    fn evaluates_with_schedule() {
+        let term = FcmcTerm::term1();
+        let options = RunOptions {
+            schedule: Schedule::Random { seed: 7 },
+            ..RunOptions::default()
+        };
+        let ans = FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options);
+        assert_eq!(
+            ans,
+            Ok(vec![("~out".to_string(), FcmcTerm::new_variable("x"))])
+        );
+    }
+
+    /// Run a term whose second forked thread never finishes on `schedule`, after the main thread
+    /// has pushed `y` to `~out`
+    fn run_forever(schedule: Schedule, options: RunOptions) -> Outcome {
+        let term: FcmcTerm = "{~a<x>.x}.{[b<x>.[x]b.x]b.b<x>.[x]b.x}.[y]~out.*"
+            .parse()
+            .unwrap();
+        let options = RunOptions {
+            schedule,
+            ..options
+        };
+        FcmcProgramState::evaluate(term.clone(), Locations::infer(&term), &options)
+    }
+
+    /// Check that a run stopped once `timeout` had passed, leaving `y` on `~out`
+    fn assert_timed_out(ans: Outcome, timeout: Duration) {
+        let left = vec![(
+            "~out".to_string(),
+            FcmcClosure::new(FcmcTerm::new_variable("y"), vec![]),
+        )];
+        assert_eq!(
+            ans,
+            Err(MachineError::TimedOut {
+                timeout,
+                memory: left
+            })
+        );
+    }
+
+    #[test]
+    fn round_robin_stops_after_timeout() {
+        let timeout = Duration::from_millis(50);
+        let options = RunOptions {
+            timeout: Some(timeout),
+            ..RunOptions::default()
+        };
+        assert_timed_out(run_forever(Schedule::RoundRobin, options), timeout);
+    }
+
+    #[test]
+    fn random_stops_after_timeout() {
+        let timeout = Duration::from_millis(50);
+        let options = RunOptions {
+            timeout: Some(timeout),
+            ..RunOptions::default()
+        };
+        assert_timed_out(run_forever(Schedule::Random { seed: 3 }, options), timeout);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        for schedule in [Schedule::RoundRobin, Schedule::Random { seed: 3 }] {
+            let cancel = CancelToken::new();
+            let options = RunOptions {
+                cancel: Some(cancel.clone()),
+                ..RunOptions::default()
+            };
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                cancel.cancel();
+            });
+            let left = vec![(
+                "~out".to_string(),
+                FcmcClosure::new(FcmcTerm::new_variable("y"), vec![]),
+            )];
+            assert_eq!(
+                run_forever(schedule, options),
+                Err(MachineError::Cancelled { memory: left })
+            );
+            canceller.join().unwrap();
+        }
+    }
+}
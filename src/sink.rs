@@ -0,0 +1,108 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Where a run writes the states it passes through and its output. Nothing is written unless a
+/// run is given a sink other than [`Sink::None`]. Clones write to the same place, so the threads
+/// of an FCMC run share one sink
+#[derive(Clone, Default)]
+pub enum Sink {
+    /// Write nothing
+    #[default]
+    None,
+    /// Write to standard output
+    Stdout,
+    /// Write to a writer shared by the clones of the sink
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    /// Keep what is written in memory, to be read with [`contents`][Sink::contents]
+    Buffer(Arc<Mutex<Vec<u8>>>),
+}
+
+impl Sink {
+    /// Create a [`Sink`] that writes to `writer`
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        Sink::Writer(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Create an empty [`Buffer`][Sink::Buffer]
+    pub fn buffer() -> Self {
+        Sink::Buffer(Arc::default())
+    }
+
+    /// Returns true if nothing written to the sink is kept
+    pub fn is_none(&self) -> bool {
+        matches!(self, Sink::None)
+    }
+
+    /// Write `line` and a newline. What is written only shows how a run is going, so a run does
+    /// not stop if it cannot be written
+    pub fn line(&self, line: fmt::Arguments<'_>) {
+        let _ = match self {
+            Sink::None => Ok(()),
+            Sink::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Sink::Writer(writer) => writeln!(writer.lock().unwrap(), "{}", line),
+            Sink::Buffer(buffer) => writeln!(buffer.lock().unwrap(), "{}", line),
+        };
+    }
+
+    /// Everything written to a [`Buffer`][Sink::Buffer] so far, or [`None`] for any other sink
+    pub fn contents(&self) -> Option<String> {
+        match self {
+            Sink::Buffer(buffer) => Some(String::from_utf8_lossy(&buffer.lock().unwrap()).into()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::None => write!(f, "None"),
+            Sink::Stdout => write!(f, "Stdout"),
+            Sink::Writer(_) => write!(f, "Writer"),
+            Sink::Buffer(_) => write!(f, "Buffer"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sink::Sink;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn clones_share_buffer() {
+        let sink = Sink::buffer();
+        sink.line(format_args!("a {}", 1));
+        let clone = sink.clone();
+        std::thread::spawn(move || clone.line(format_args!("b")))
+            .join()
+            .unwrap();
+        assert_eq!(sink.contents(), Some("a 1\nb\n".to_string()));
+        assert_eq!(Sink::None.contents(), None);
+    }
+
+    /// A writer whose output can still be read once it has been given to a sink
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_to_writer() {
+        let shared = Shared::default();
+        let sink = Sink::writer(shared.clone());
+        sink.line(format_args!("x"));
+        assert_eq!(*shared.0.lock().unwrap(), b"x\n");
+    }
+}